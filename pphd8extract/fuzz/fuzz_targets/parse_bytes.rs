@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pphd8extract::pphd8parser::PPHD8FileData;
+
+// Parse arbitrary bytes as a PPHD8 file and read every entry, both copying and borrowing them
+fuzz_target!(|data: &[u8]| {
+    if let Ok(pphd8_file) = PPHD8FileData::parse_from_bytes(data) {
+        let _ = pphd8_file.to_string();
+        let _ = pphd8_file.get_vag_files();
+        let _ = pphd8_file.get_vag_files_borrowed();
+    }
+});
@@ -0,0 +1,14 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use pphd8extract::pphd8parser::PPHD8FileData;
+
+// Same as `parse_bytes`, but going through the `Read + Seek` adapter
+fuzz_target!(|data: &[u8]| {
+    if let Ok(pphd8_file) = PPHD8FileData::parse_from_reader(Cursor::new(data)) {
+        let _ = pphd8_file.to_string();
+        let _ = pphd8_file.get_vag_files();
+    }
+});
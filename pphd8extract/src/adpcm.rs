@@ -0,0 +1,511 @@
+// PS-ADPCM (SPU2 VAG) decoding and encoding.
+//
+// A VAG body is a sequence of 16 bytes blocks. Each block has:
+// - 1 byte with the filter (predictor) in the high nibble and the shift in the low nibble
+// - 1 byte with loop/end flags
+// - 14 bytes of data, 28 signed nibbles, low nibble first. Each nibble is a sample.
+
+/// Size in bytes of a PS-ADPCM block
+pub const BLOCK_SIZE: usize = 16;
+/// Number of samples encoded in each PS-ADPCM block
+pub const SAMPLES_PER_BLOCK: usize = 28;
+
+/// Last block of the sound, or of the loop if [`FLAG_LOOP_REPEAT`] is also set
+pub const FLAG_LOOP_END: u8 = 0x01;
+/// The sound loops, used together with [`FLAG_LOOP_START`] and [`FLAG_LOOP_END`]
+pub const FLAG_LOOP_REPEAT: u8 = 0x02;
+/// First block of the loop region
+pub const FLAG_LOOP_START: u8 = 0x04;
+/// All flags set at once, some encoders add a silent block with it after the last block
+pub const FLAG_END_MARKER: u8 = 0x07;
+
+/// Bits per sample of decoded audio, and of the files written from it
+pub const BITS_PER_SAMPLE: u16 = 16;
+/// MIDI note played at the original pitch, used by samplers to map decoded audio to keys.
+/// 60 is middle C.
+pub const MIDI_UNITY_NOTE: u8 = 60;
+
+/// Highest sample rate of decoded and imported audio, the most a FLAC stream can store.
+/// Anything higher is a typo or a corrupt file, and would take gigabytes per second.
+pub const MAX_SAMPLE_RATE: u32 = (1 << 20) - 1;
+
+/// Filter coefficients (positive, negative), scaled by 64
+const FILTERS: [(i32, i32); 5] = [(0, 0), (60, 0), (115, -52), (98, -55), (122, -60)];
+
+/// Audio decoded from a VAG body: 16 bits PCM at the entry's frequency
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedAudio {
+    /// Samples of all channels, interleaved
+    pub samples: Vec<i16>,
+    pub sample_rate: u32,
+    /// Number of channels, 1 for most files
+    pub channels: u16,
+    /// Loop region, if the body has loop flags
+    pub loop_region: Option<LoopRegion>,
+}
+
+impl DecodedAudio {
+    /// Number of samples in each channel
+    pub fn frame_count(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+}
+
+/// Region of a sound that repeats after playing it once, in samples of each channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopRegion {
+    /// First sample of the loop
+    pub start: usize,
+    /// Sample right after the end of the loop (exclusive)
+    pub end: usize,
+}
+
+/// Decoder state, the last two samples are needed to predict the next ones.
+///
+/// Use it directly to decode a body block by block, or use [`decode`] for a whole body.
+#[derive(Debug, Default, Clone)]
+pub struct AdpcmDecoder {
+    hist1: i32,
+    hist2: i32,
+}
+
+impl AdpcmDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode the 28 samples of a block and append them to `output`
+    pub fn decode_block(&mut self, block: &[u8; BLOCK_SIZE], output: &mut Vec<i16>) {
+        let mut filter = (block[0] >> 4) as usize;
+        let mut shift = (block[0] & 0xF) as u32;
+
+        // Invalid values, the SPU treats shifts over 12 as 9 and we ignore unknown filters
+        if filter >= FILTERS.len() {
+            filter = 0;
+        }
+        if shift > 12 {
+            shift = 9;
+        }
+        let (coef1, coef2) = FILTERS[filter];
+
+        for byte in &block[2..] {
+            for nibble in [byte & 0xF, byte >> 4] {
+                // Sign extend the nibble to 16 bits and scale it
+                let sample = ((((nibble as i16) << 12) as i32) >> shift)
+                    + ((self.hist1 * coef1 + self.hist2 * coef2 + 32) >> 6);
+                let sample = sample.clamp(i16::MIN as i32, i16::MAX as i32);
+
+                self.hist2 = self.hist1;
+                self.hist1 = sample;
+                output.push(sample as i16);
+            }
+        }
+    }
+}
+
+/// Flags byte of a block
+pub fn block_flags(block: &[u8; BLOCK_SIZE]) -> u8 {
+    block[1]
+}
+
+/// Decode a whole VAG body to PCM samples.
+///
+/// Decoding stops after the first block with [`FLAG_LOOP_END`] (looping sounds are decoded
+/// only once), or before a block that only marks the end of the sound. A trailing incomplete
+/// block is ignored.
+pub fn decode(body: &[u8]) -> Vec<i16> {
+    let mut decoder = AdpcmDecoder::new();
+    let mut samples = Vec::with_capacity(body.len() / BLOCK_SIZE * SAMPLES_PER_BLOCK);
+
+    for block in body.chunks_exact(BLOCK_SIZE) {
+        let Ok(block) = <&[u8; BLOCK_SIZE]>::try_from(block) else {
+            break;
+        };
+        let flags = block_flags(block);
+        if flags == FLAG_END_MARKER {
+            break;
+        }
+
+        decoder.decode_block(block, &mut samples);
+
+        if flags & FLAG_LOOP_END != 0 {
+            break;
+        }
+    }
+
+    samples
+}
+
+/// Find the loop region of a VAG body by looking at the block flags, without decoding it.
+///
+/// The loop goes from the last block with [`FLAG_LOOP_START`] to the end of the block with
+/// [`FLAG_LOOP_END`], as long as that block also has [`FLAG_LOOP_REPEAT`]. Sample positions
+/// match the samples returned by [`decode`].
+pub fn find_loop(body: &[u8]) -> Option<LoopRegion> {
+    let mut loop_start = 0;
+
+    for (i, block) in body.chunks_exact(BLOCK_SIZE).enumerate() {
+        let flags = block[1];
+        if flags == FLAG_END_MARKER {
+            return None;
+        }
+
+        if flags & FLAG_LOOP_START != 0 {
+            loop_start = i;
+        }
+
+        if flags & FLAG_LOOP_END != 0 {
+            if flags & FLAG_LOOP_REPEAT == 0 {
+                return None;
+            }
+
+            return Some(LoopRegion {
+                start: loop_start * SAMPLES_PER_BLOCK,
+                end: (i + 1) * SAMPLES_PER_BLOCK,
+            });
+        }
+    }
+
+    None
+}
+
+/// Highest shift the SPU understands, see [`AdpcmDecoder::decode_block`]
+const MAX_SHIFT: u32 = 12;
+
+/// Encoder state, it tracks the samples the decoder will get so errors don't add up.
+///
+/// Use it directly to encode samples block by block, or use [`encode`] for a whole sound.
+#[derive(Debug, Default, Clone)]
+pub struct AdpcmEncoder {
+    hist1: i32,
+    hist2: i32,
+}
+
+impl AdpcmEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encode 28 samples into a block with the given flags.
+    ///
+    /// Every filter and shift is tried, and the block uses the pair with the smallest error.
+    pub fn encode_block(
+        &mut self,
+        samples: &[i16; SAMPLES_PER_BLOCK],
+        flags: u8,
+    ) -> [u8; BLOCK_SIZE] {
+        let (filter, shift) = (0..FILTERS.len())
+            .flat_map(|filter| (0..=MAX_SHIFT).map(move |shift| (filter, shift)))
+            .min_by_key(|(filter, shift)| {
+                self.clone()
+                    .encode_samples(samples, *filter, *shift, |_| {})
+            })
+            .unwrap_or((0, MAX_SHIFT));
+
+        let mut block = [0u8; BLOCK_SIZE];
+        block[0] = ((filter as u8) << 4) | shift as u8;
+        block[1] = flags;
+        let mut nibbles = Vec::with_capacity(SAMPLES_PER_BLOCK);
+        self.encode_samples(samples, filter, shift, |nibble| nibbles.push(nibble));
+        for (byte, pair) in block[2..].iter_mut().zip(nibbles.chunks_exact(2)) {
+            *byte = pair[0] | (pair[1] << 4);
+        }
+
+        block
+    }
+
+    /// Encode samples with a filter and shift, passing each nibble to `output`.
+    ///
+    /// Returns the squared error of the samples the decoder will produce.
+    fn encode_samples(
+        &mut self,
+        samples: &[i16; SAMPLES_PER_BLOCK],
+        filter: usize,
+        shift: u32,
+        mut output: impl FnMut(u8),
+    ) -> u64 {
+        let (coef1, coef2) = FILTERS[filter];
+        let mut error = 0;
+
+        for sample in samples {
+            let prediction = (self.hist1 * coef1 + self.hist2 * coef2 + 32) >> 6;
+            let residual = *sample as i32 - prediction;
+            // Round to the closest nibble, the decoder does `(nibble << 12) >> shift`
+            let nibble = (((residual << shift) + (1 << 11)) >> 12).clamp(-8, 7);
+
+            // Same steps as the decoder, so the history matches what it will have
+            let decoded = ((((nibble as i16) << 12) as i32) >> shift) + prediction;
+            let decoded = decoded.clamp(i16::MIN as i32, i16::MAX as i32);
+            self.hist2 = self.hist1;
+            self.hist1 = decoded;
+
+            let difference = (*sample as i32 - decoded) as i64;
+            error += (difference * difference) as u64;
+            output((nibble & 0xF) as u8);
+        }
+
+        error
+    }
+}
+
+/// Encode PCM samples to a VAG body.
+///
+/// Without a loop, the last block has [`FLAG_LOOP_END`] so the sound stops there. With a loop,
+/// its blocks have [`FLAG_LOOP_REPEAT`], the first one also [`FLAG_LOOP_START`] and the last
+/// one [`FLAG_LOOP_END`]. Anything after the loop is never played, so it's dropped.
+///
+/// Loops can only start and end at block boundaries. Silence is added at the start until the
+/// loop start falls on one, and the last block of the loop is completed with the samples from
+/// the loop start, so use [`find_loop`] on the body to get the final loop points.
+pub fn encode(samples: &[i16], loop_region: Option<LoopRegion>) -> Vec<u8> {
+    let loop_region = loop_region.filter(|loop_region| {
+        loop_region.start < loop_region.end && loop_region.end <= samples.len()
+    });
+
+    // Samples actually encoded, with the loop aligned to blocks
+    let mut padded = vec![];
+    let loop_blocks = match loop_region {
+        Some(LoopRegion { start, end }) => {
+            let lead = (SAMPLES_PER_BLOCK - start % SAMPLES_PER_BLOCK) % SAMPLES_PER_BLOCK;
+            padded.resize(lead, 0);
+            padded.extend_from_slice(&samples[..end]);
+            let loop_samples = samples[start..end].iter().cycle();
+            let missing =
+                (SAMPLES_PER_BLOCK - padded.len() % SAMPLES_PER_BLOCK) % SAMPLES_PER_BLOCK;
+            padded.extend(loop_samples.take(missing));
+
+            Some((
+                (start + lead) / SAMPLES_PER_BLOCK,
+                padded.len() / SAMPLES_PER_BLOCK,
+            ))
+        }
+        None => {
+            padded.extend_from_slice(samples);
+            let missing =
+                (SAMPLES_PER_BLOCK - padded.len() % SAMPLES_PER_BLOCK) % SAMPLES_PER_BLOCK;
+            padded.resize(padded.len() + missing, 0);
+            // An empty sound still needs a block to end it
+            if padded.is_empty() {
+                padded.resize(SAMPLES_PER_BLOCK, 0);
+            }
+            None
+        }
+    };
+
+    let n_blocks = padded.len() / SAMPLES_PER_BLOCK;
+    let mut encoder = AdpcmEncoder::new();
+    let mut body = Vec::with_capacity(n_blocks * BLOCK_SIZE);
+    for (i, block_samples) in padded.chunks_exact(SAMPLES_PER_BLOCK).enumerate() {
+        let flags = match loop_blocks {
+            Some((loop_start, loop_end)) => {
+                let mut flags = 0;
+                if i >= loop_start {
+                    flags |= FLAG_LOOP_REPEAT;
+                }
+                if i == loop_start {
+                    flags |= FLAG_LOOP_START;
+                }
+                if i == loop_end - 1 {
+                    flags |= FLAG_LOOP_END;
+                }
+                flags
+            }
+            None if i == n_blocks - 1 => FLAG_LOOP_END,
+            None => 0,
+        };
+
+        let Ok(block_samples) = <&[i16; SAMPLES_PER_BLOCK]>::try_from(block_samples) else {
+            break;
+        };
+        body.extend_from_slice(&encoder.encode_block(block_samples, flags));
+    }
+
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(len: usize) -> Vec<i16> {
+        (0..len)
+            .map(|i| (10000.0 * (i as f64 * 2.0 * std::f64::consts::PI / 50.0).sin()) as i16)
+            .collect()
+    }
+
+    /// Signal to noise ratio in dB of `decoded` against `original`
+    fn snr(original: &[i16], decoded: &[i16]) -> f64 {
+        assert_eq!(original.len(), decoded.len());
+        let (signal, noise) =
+            original
+                .iter()
+                .zip(decoded)
+                .fold((0.0, 0.0), |(signal, noise), (a, b)| {
+                    let (a, b) = (*a as f64, *b as f64);
+                    (signal + a * a, noise + (a - b) * (a - b))
+                });
+        10.0 * (signal / noise).log10()
+    }
+
+    fn flags(body: &[u8]) -> Vec<u8> {
+        body.chunks_exact(BLOCK_SIZE)
+            .map(|block| block[1])
+            .collect()
+    }
+
+    /// A block with the given header byte, flags and first data bytes, the rest are zeros
+    fn block(header: u8, flags: u8, data: &[u8]) -> [u8; BLOCK_SIZE] {
+        let mut block = [0u8; BLOCK_SIZE];
+        block[0] = header;
+        block[1] = flags;
+        block[2..2 + data.len()].copy_from_slice(data);
+        block
+    }
+
+    fn decode_blocks(blocks: &[[u8; BLOCK_SIZE]]) -> Vec<i16> {
+        let mut decoder = AdpcmDecoder::new();
+        let mut samples = vec![];
+        for block in blocks {
+            decoder.decode_block(block, &mut samples);
+        }
+        samples
+    }
+
+    #[test]
+    fn filter_0_only_scales_the_nibbles() {
+        // Low nibble first: 1, -1, 7, -8
+        let samples = decode_blocks(&[block(0x0C, 0, &[0xF1, 0x87])]);
+        assert_eq!(samples[..5], [1, -1, 7, -8, 0]);
+
+        // 7 << 12, then -8 << 12
+        let samples = decode_blocks(&[block(0x00, 0, &[0x87])]);
+        assert_eq!(samples[..3], [28672, -32768, 0]);
+    }
+
+    #[test]
+    fn filters_predict_from_the_last_two_samples() {
+        // Every sample after the first one is (hist1 * coef1 + hist2 * coef2 + 32) >> 6
+        // Filter 1: 60 / 64
+        let samples = decode_blocks(&[block(0x10, 0, &[0x07])]);
+        assert_eq!(samples[..4], [28672, 26880, 25200, 23625]);
+        // Rounding goes down for negative values too: (-60 + 32) >> 6 = -1
+        let samples = decode_blocks(&[block(0x1C, 0, &[0x0F])]);
+        assert_eq!(samples[..3], [-1, -1, -1]);
+
+        // Filter 2: 115 / 64 and -52 / 64, the third sample is clamped to 32767 and
+        // the next ones are predicted from the clamped value
+        let samples = decode_blocks(&[block(0x20, 0, &[0x04])]);
+        assert_eq!(samples[..5], [16384, 29440, 32767, 32767, 32255]);
+
+        // Filter 3: 98 / 64 and -55 / 64, with a shift of 4, 1 << 12 >> 4 = 256
+        let samples = decode_blocks(&[block(0x34, 0, &[0x01])]);
+        assert_eq!(samples[..4], [256, 392, 380, 245]);
+
+        // Filter 4: 122 / 64 and -60 / 64
+        let samples = decode_blocks(&[block(0x44, 0, &[0x01])]);
+        assert_eq!(samples[..4], [256, 488, 690, 858]);
+    }
+
+    #[test]
+    fn invalid_shifts_and_filters_are_clamped() {
+        // Shifts over 12 are 9: 1 << 12 >> 9 = 8
+        for header in [0x0D, 0x0F] {
+            let samples = decode_blocks(&[block(header, 0, &[0x01])]);
+            assert_eq!(samples[..2], [8, 0]);
+        }
+
+        // Unknown filters are 0, so the samples of the block before are not used
+        let samples = decode_blocks(&[block(0x10, 0, &[0x07]), block(0x5C, 0, &[0x01])]);
+        assert_eq!(samples[SAMPLES_PER_BLOCK - 1], 5019);
+        assert_eq!(samples[SAMPLES_PER_BLOCK..SAMPLES_PER_BLOCK + 2], [1, 0]);
+    }
+
+    #[test]
+    fn decode_stops_at_the_end_flags() {
+        let sound = block(0x0C, 0, &[0x11]);
+        let concat = |blocks: &[[u8; BLOCK_SIZE]]| blocks.concat();
+
+        // A block with every flag only marks the end, it is not decoded
+        let samples = decode(&concat(&[
+            sound,
+            block(0x0C, FLAG_END_MARKER, &[0x11]),
+            sound,
+        ]));
+        assert_eq!(samples.len(), SAMPLES_PER_BLOCK);
+
+        // The block with the loop end is the last one decoded, with or without a loop
+        for flags in [FLAG_LOOP_END, FLAG_LOOP_END | FLAG_LOOP_REPEAT] {
+            let samples = decode(&concat(&[sound, block(0x0C, flags, &[0x22]), sound]));
+            assert_eq!(samples.len(), 2 * SAMPLES_PER_BLOCK);
+            assert_eq!(samples[SAMPLES_PER_BLOCK..SAMPLES_PER_BLOCK + 2], [2, 2]);
+        }
+
+        // Without end flags every whole block is decoded, the incomplete one is ignored
+        let mut body = concat(&[sound, sound]);
+        body.extend([0x0C, 0, 0x11]);
+        assert_eq!(decode(&body).len(), 2 * SAMPLES_PER_BLOCK);
+    }
+
+    #[test]
+    fn encoded_samples_decode_back_close_to_the_original() {
+        let samples = sine(3000);
+        let body = encode(&samples, None);
+
+        // Completed with silence up to a whole block
+        assert_eq!(body.len(), 108 * BLOCK_SIZE);
+        let decoded = decode(&body);
+        assert_eq!(decoded.len(), 108 * SAMPLES_PER_BLOCK);
+        let mut padded = samples.clone();
+        padded.resize(decoded.len(), 0);
+        assert!(snr(&padded, &decoded) > 40.0);
+
+        // Only the last block stops the sound
+        let flags = flags(&body);
+        assert!(flags[..107].iter().all(|flags| *flags == 0));
+        assert_eq!(flags[107], FLAG_LOOP_END);
+        assert_eq!(find_loop(&body), None);
+    }
+
+    #[test]
+    fn loops_are_moved_to_block_boundaries() {
+        let samples = sine(3500);
+        let body = encode(
+            &samples,
+            Some(LoopRegion {
+                start: 100,
+                end: 3000,
+            }),
+        );
+
+        // 12 samples of silence move the loop start from 100 to 112, the fourth block. The
+        // loop ends at 3012, and the last block is completed with the start of the loop.
+        assert_eq!(
+            find_loop(&body),
+            Some(LoopRegion {
+                start: 112,
+                end: 3024
+            })
+        );
+        let flags = flags(&body);
+        assert_eq!(flags.len(), 108);
+        assert!(flags[..4].iter().all(|flags| *flags == 0));
+        assert_eq!(flags[4], FLAG_LOOP_START | FLAG_LOOP_REPEAT);
+        assert!(flags[5..107].iter().all(|flags| *flags == FLAG_LOOP_REPEAT));
+        assert_eq!(flags[107], FLAG_LOOP_END | FLAG_LOOP_REPEAT);
+
+        // Samples after the loop end are dropped
+        let decoded = decode(&body);
+        assert_eq!(decoded.len(), 3024);
+        assert!(decoded[..12].iter().all(|sample| sample.abs() < 64));
+        assert!(snr(&samples[..3000], &decoded[12..3012]) > 40.0);
+        assert!(snr(&samples[100..112], &decoded[3012..]) > 30.0);
+    }
+
+    #[test]
+    fn empty_sound_is_a_single_silent_block() {
+        let body = encode(&[], None);
+        assert_eq!(body.len(), BLOCK_SIZE);
+        assert_eq!(flags(&body), [FLAG_LOOP_END]);
+        assert!(decode(&body).iter().all(|sample| *sample == 0));
+    }
+}
@@ -0,0 +1,156 @@
+// AIFF writer for decoded VAG files
+
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::adpcm::{DecodedAudio, LoopRegion, BITS_PER_SAMPLE, MIDI_UNITY_NOTE};
+
+/// Marker ids used for the start and end of the loop
+const LOOP_START_MARKER_ID: u16 = 1;
+const LOOP_END_MARKER_ID: u16 = 2;
+/// Loop play modes of the `INST` chunk
+const PLAY_MODE_NO_LOOP: u16 = 0;
+const PLAY_MODE_FORWARD: u16 = 1;
+
+/// Write decoded audio as a 16 bits PCM AIFF file.
+///
+/// If the audio has a loop region, its start and end are written as markers in a `MARK`
+/// chunk, and the `INST` chunk uses them as a forward sustain loop.
+pub fn write_aiff<W: Write>(writer: &mut W, audio: &DecodedAudio) -> io::Result<()> {
+    let channels = audio.channels;
+    let too_long = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Too much audio for an AIFF file",
+        )
+    };
+    let n_frames = u32::try_from(audio.frame_count()).map_err(|_| too_long())?;
+    let data_size = u32::try_from(audio.samples.len())
+        .ok()
+        .and_then(|n_samples| n_samples.checked_mul((BITS_PER_SAMPLE / 8) as u32))
+        .ok_or_else(too_long)?;
+
+    let loop_chunks = match audio.loop_region {
+        Some(loop_region) => loop_chunks(loop_region)?,
+        None => vec![],
+    };
+
+    // FORM header, its size counts everything after the size field. The data size is always
+    // even, so no chunk needs padding.
+    let comm_chunk_size: u32 = 18;
+    let ssnd_chunk_size = data_size.checked_add(8).ok_or_else(too_long)?;
+    let form_size = u32::try_from(loop_chunks.len())
+        .ok()
+        .and_then(|loop_size| (4 + 8 + comm_chunk_size + 8).checked_add(loop_size))
+        .and_then(|size| size.checked_add(ssnd_chunk_size))
+        .ok_or_else(too_long)?;
+    writer.write_all(b"FORM")?;
+    writer.write_all(&form_size.to_be_bytes())?;
+    writer.write_all(b"AIFF")?;
+
+    // Common chunk
+    writer.write_all(b"COMM")?;
+    writer.write_all(&comm_chunk_size.to_be_bytes())?;
+    writer.write_all(&channels.to_be_bytes())?;
+    writer.write_all(&n_frames.to_be_bytes())?;
+    writer.write_all(&BITS_PER_SAMPLE.to_be_bytes())?;
+    writer.write_all(&extended_from_u32(audio.sample_rate))?;
+
+    writer.write_all(&loop_chunks)?;
+
+    // Sound data chunk, with no offset or block alignment
+    writer.write_all(b"SSND")?;
+    writer.write_all(&ssnd_chunk_size.to_be_bytes())?;
+    writer.write_all(&0u32.to_be_bytes())?;
+    writer.write_all(&0u32.to_be_bytes())?;
+    for sample in &audio.samples {
+        writer.write_all(&sample.to_be_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Write decoded audio to an AIFF file in disk
+pub fn write_aiff_file(filepath: &Path, audio: &DecodedAudio) -> io::Result<()> {
+    let mut writer = BufWriter::new(fs::File::create(filepath)?);
+    write_aiff(&mut writer, audio)?;
+    writer.flush()
+}
+
+/// Build the `MARK` and `INST` chunks for a loop region
+fn loop_chunks(loop_region: LoopRegion) -> io::Result<Vec<u8>> {
+    let to_u32 = |position: usize| {
+        u32::try_from(position).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Loop point out of range for an AIFF file",
+            )
+        })
+    };
+
+    // AIFF markers are placed between samples, so the exclusive end is already right
+    let markers = [
+        (
+            LOOP_START_MARKER_ID,
+            to_u32(loop_region.start)?,
+            "Loop start",
+        ),
+        (LOOP_END_MARKER_ID, to_u32(loop_region.end)?, "Loop end"),
+    ];
+
+    let mut mark_chunk = vec![];
+    mark_chunk.extend_from_slice(&(markers.len() as u16).to_be_bytes());
+    for (id, position, name) in markers {
+        mark_chunk.extend_from_slice(&id.to_be_bytes());
+        mark_chunk.extend_from_slice(&position.to_be_bytes());
+        // Pascal string, padded so the count byte plus the text have an even length
+        mark_chunk.push(name.len() as u8);
+        mark_chunk.extend_from_slice(name.as_bytes());
+        if name.len() % 2 == 0 {
+            mark_chunk.push(0);
+        }
+    }
+
+    let mut chunks = vec![];
+    chunks.extend_from_slice(b"MARK");
+    chunks.extend_from_slice(&(mark_chunk.len() as u32).to_be_bytes());
+    chunks.extend_from_slice(&mark_chunk);
+
+    // Instrument chunk: notes and velocities cover the whole range, and the sustain loop
+    // goes between our markers
+    chunks.extend_from_slice(b"INST");
+    chunks.extend_from_slice(&20u32.to_be_bytes());
+    chunks.extend_from_slice(&[MIDI_UNITY_NOTE, 0, 0, 127, 1, 127]);
+    chunks.extend_from_slice(&0u16.to_be_bytes()); // gain
+    for word in [
+        PLAY_MODE_FORWARD,
+        LOOP_START_MARKER_ID,
+        LOOP_END_MARKER_ID,
+        // Release loop
+        PLAY_MODE_NO_LOOP,
+        0,
+        0,
+    ] {
+        chunks.extend_from_slice(&word.to_be_bytes());
+    }
+
+    Ok(chunks)
+}
+
+/// Convert an integer to the 80 bits IEEE 754 extended float used by AIFF for sample rates
+fn extended_from_u32(value: u32) -> [u8; 10] {
+    let mut bytes = [0u8; 10];
+    if value == 0 {
+        return bytes;
+    }
+
+    // The mantissa has an explicit integer bit, so we only need to normalize it
+    let leading_zeros = (value as u64).leading_zeros();
+    let exponent = 16383 + 63 - leading_zeros as u16;
+    let mantissa = (value as u64) << leading_zeros;
+
+    bytes[..2].copy_from_slice(&exponent.to_be_bytes());
+    bytes[2..].copy_from_slice(&mantissa.to_be_bytes());
+    bytes
+}
@@ -1,133 +1,899 @@
 // Rust imports
+use std::fs;
+use std::io::{self, BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::process::exit;
 
 // Third party imports
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use rayon::prelude::*;
 
 // Local imports
 extern crate pphd8extract;
-use pphd8extract::pphd8parser::{PPHD8FileData, VAGFile};
+use pphd8extract::adpcm::{DecodedAudio, MAX_SAMPLE_RATE};
+use pphd8extract::export::{export_vag_file, EntrySource, ExportFormat};
+use pphd8extract::pphd8parser::{
+    PPHD8FileData, ParseError, VAGFile, VAGVersion, DEFAULT_VAGI_INTERLEAVE,
+};
+use pphd8extract::pphd8writer::{merge_banks, MergeHeader, MergedEntry, PPHD8Writer};
+use pphd8extract::wav;
 
 /// Extract the content of a pphd8 file, getting the list of VAG files
 #[derive(Parser, Debug)]
 #[command(author = "Luis Diaz", version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Extract all the files, used when there is no command
+    #[command(flatten)]
+    extract: Option<ExtractArgs>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Decode a single entry and write it to stdout, to pipe it into other programs
+    Stream(StreamArgs),
+    /// Print the header of standalone VAG files
+    InspectVag(InspectVagArgs),
+    /// Convert standalone VAG files, like previously extracted ones, to WAV or other formats
+    ConvertVag(ConvertVagArgs),
+    /// Encode WAV files as standalone VAG files
+    ImportWav(ImportWavArgs),
+    /// Replace an entry of a PPHD8 file with a VAG or WAV file, saving the result as a new file
+    ReplaceEntry(ReplaceEntryArgs),
+    /// Change the frequency, order or presence of the entries of a PPHD8 file, saving the
+    /// result as a new file
+    Edit(EditArgs),
+    /// Merge the entries of several PPHD8 files into a new one
+    Merge(MergeArgs),
+}
+
+#[derive(Args, Debug)]
+struct ExtractArgs {
     /// file to decompress
     pphd8_file: PathBuf,
 
     /// Where to save resulting VAG files
     target_dir: PathBuf,
 
+    /// Format of the extracted files: vag (raw ADPCM), wav, aiff or flac (decoded PCM). Defaults to vag.
+    #[arg(short, long, default_value_t = ExportFormat::Vag)]
+    format: ExportFormat,
+
+    /// Version written in the header of VAG files, like 0x20 or 3. Defaults to 0x20.
+    #[arg(long, default_value_t = VAGVersion::default())]
+    vag_version: VAGVersion,
+
+    /// Save consecutive entries as the left and right channels of a single stereo file,
+    /// for banks that store stereo sounds as two entries. Defaults to false.
+    #[arg(long, default_value_t = false)]
+    stereo_pairs: bool,
+
     /// Print more details about the extraction process. Defaults to false.
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
 }
 
-impl Cli {
+#[derive(Args, Debug)]
+struct StreamArgs {
+    /// file to read the entry from
+    pphd8_file: PathBuf,
+
+    /// Index of the entry to decode
+    entry: usize,
+
+    /// Format of the output: raw (16 bits little endian mono PCM, no header) or wav. Defaults to raw.
+    #[arg(short, long, value_enum, default_value_t = StreamFormat::Raw)]
+    format: StreamFormat,
+}
+
+#[derive(Args, Debug)]
+struct InspectVagArgs {
+    /// VAG file, or directory with VAG files, to inspect
+    input: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct ConvertVagArgs {
+    /// VAG file, or directory with VAG files, to convert
+    input: PathBuf,
+
+    /// Where to save converted files
+    target_dir: PathBuf,
+
+    /// Format of the converted files: vag, wav, aiff or flac. Defaults to wav.
+    #[arg(short, long, default_value_t = ExportFormat::Wav)]
+    format: ExportFormat,
+
+    /// Version written in the header of VAG files, like 0x20 or 3. Keeps the original one if missing.
+    #[arg(long)]
+    vag_version: Option<VAGVersion>,
+}
+
+#[derive(Args, Debug)]
+struct ImportWavArgs {
+    /// WAV file, or directory with WAV files, to import
+    input: PathBuf,
+
+    /// Where to save the VAG files
+    target_dir: PathBuf,
+
+    /// Sample rate of the VAG files, in Hz. Keeps the one of the WAV file if missing.
+    #[arg(long)]
+    sample_rate: Option<u32>,
+
+    /// Version written in the header of VAG files, like 0x20 or 3. Defaults to 0x20.
+    #[arg(long, default_value_t = VAGVersion::default())]
+    vag_version: VAGVersion,
+}
+
+#[derive(Args, Debug)]
+struct ReplaceEntryArgs {
+    /// file with the entry to replace
+    pphd8_file: PathBuf,
+
+    /// Index of the entry to replace
+    entry: usize,
+
+    /// Mono VAG or WAV file with the new sound
+    input: PathBuf,
+
+    /// Where to save the new PPHD8 file, it can't be the original one
+    output_file: PathBuf,
+
+    /// Sample rate to convert WAV files to, in Hz. Keeps the one of the WAV file if missing.
+    #[arg(long)]
+    sample_rate: Option<u32>,
+}
+
+/// Entries are always given by their index in the original file
+#[derive(Args, Debug)]
+struct EditArgs {
+    /// file to edit
+    pphd8_file: PathBuf,
+
+    /// Where to save the new PPHD8 file, it can't be the original one
+    output_file: PathBuf,
+
+    /// New sample rate of an entry, as INDEX=HZ. Can be repeated.
+    #[arg(long = "frequency", value_name = "INDEX=HZ", value_parser = parse_frequency_edit)]
+    frequencies: Vec<(usize, u32)>,
+
+    /// New order of the entries, as a comma separated list with all of them, like 2,0,1
+    #[arg(long, value_delimiter = ',')]
+    order: Option<Vec<usize>>,
+
+    /// Replace an entry with a null entry, so the ones after it keep their slot in the
+    /// index. Can be repeated.
+    #[arg(long = "null", value_name = "INDEX")]
+    nulls: Vec<usize>,
+
+    /// Remove an entry and its slot in the index. Can be repeated.
+    #[arg(long = "remove", value_name = "INDEX")]
+    removals: Vec<usize>,
+}
+
+#[derive(Args, Debug)]
+struct MergeArgs {
+    /// Where to save the merged PPHD8 file, it can't be one of the merged ones
+    output_file: PathBuf,
+
+    /// files to merge, in order. The entries of the first one keep their index.
+    #[arg(required = true, num_args = 2..)]
+    pphd8_files: Vec<PathBuf>,
+
+    /// Write a new header instead of keeping the one of the first file. Defaults to false.
+    #[arg(long, default_value_t = false)]
+    default_header: bool,
+
+    /// Also save the new index of every entry to this CSV file, with bank,index,new_index rows
+    #[arg(long)]
+    mapping: Option<PathBuf>,
+}
+
+/// Formats that can be written to stdout
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum StreamFormat {
+    Raw,
+    Wav,
+}
+
+impl ExtractArgs {
     /// Checks if the arguments are consistent.
     /// If not, handle errors and exit the process
     fn check(&self) {
         let pphd8_file = self.pphd8_file.as_path();
         let target_dir = self.target_dir.as_path();
 
-        Self::check_file_exists(pphd8_file);
-        Self::check_file_exists(target_dir);
-        Self::check_file_is_dir(target_dir);
+        check_file_exists(pphd8_file);
+        check_file_exists(target_dir);
+        check_file_is_dir(target_dir);
     }
 
     /// Runs the application
     fn run(&self) {
-        let file = self.parse_pphd8();
-        let vags = self.extract_vag_files(&file);
-        self.save_vag_files(&vags);
+        let file = parse_pphd8(&self.pphd8_file);
+        println!("Successfully extracted data from PPHD8File!");
+        if self.verbose {
+            println!("Result:");
+            println!("{}", file);
+        }
+
+        self.save_vag_files(&file);
+    }
+
+    fn save_vag_files(&self, file: &PPHD8FileData) {
+        println!("Extracting and saving VAG files...");
+        let bank_name = self
+            .pphd8_file
+            .file_stem()
+            .map(|stem| stem.to_string_lossy())
+            .unwrap_or_default();
+
+        // Entries that go in the same output file, a pair of them for stereo files
+        let entries: Vec<usize> = (0..file.n_files()).collect();
+        let groups: Vec<&[usize]> = entries
+            .chunks(if self.stereo_pairs { 2 } else { 1 })
+            .collect();
+
+        // Entries are read one by one, so only a few bodies are in memory at the same time
+        // and a broken entry doesn't stop the others from being saved
+        let errors: Vec<(String, ParseError)> = groups
+            .into_par_iter()
+            .map(|group| {
+                let name = group
+                    .iter()
+                    .map(|i| i.to_string())
+                    .collect::<Vec<_>>()
+                    .join("_");
+                let extension = self.format.extension();
+                let output_vag_filepath = self
+                    .target_dir
+                    .join(format!("extracted_{name}.{extension}"));
+                let output_vag_filepath = output_vag_filepath.as_path();
+                let source = EntrySource {
+                    bank_name: &bank_name,
+                    entry_index: group[0],
+                };
+                let result = Self::read_group(file, group).and_then(|vag| {
+                    let vag = vag.with_version(self.vag_version);
+                    println!("Saving file {name} to {}...", output_vag_filepath.display());
+                    Ok(export_vag_file(
+                        &vag,
+                        output_vag_filepath,
+                        self.format,
+                        Some(&source),
+                    )?)
+                });
+                (name, result)
+            })
+            .filter_map(|(name, output)| match output {
+                Err(e) => Some((name, e)),
+                _ => None,
+            })
+            .collect();
+
+        if !errors.is_empty() {
+            eprintln!("Some files could not be extracted:");
+            for (name, error) in errors {
+                eprintln!("VAG File {name} could not be extracted. Error: {error}");
+            }
+        } else {
+            println!("All files successfully extracted!");
+        }
     }
 
-    fn parse_pphd8(&self) -> PPHD8FileData {
-        let pphd8_file = PPHD8FileData::parse_from_file(&self.pphd8_file);
-        let file = match pphd8_file {
+    /// Read the entries of a group, interleaving them if there is more than one
+    fn read_group(file: &PPHD8FileData, group: &[usize]) -> Result<VAGFile<'static>, ParseError> {
+        if let [i] = group {
+            return file.read_entry(*i);
+        }
+
+        let channels = group
+            .iter()
+            .map(|i| file.read_entry(*i))
+            .collect::<Result<Vec<_>, _>>()?;
+        VAGFile::interleave_channels(&channels, DEFAULT_VAGI_INTERLEAVE)
+    }
+}
+
+impl StreamArgs {
+    /// Runs the command. Only audio goes to stdout, messages go to stderr.
+    fn run(&self) {
+        check_file_exists(&self.pphd8_file);
+
+        let file = parse_pphd8(&self.pphd8_file);
+        let audio = match file.read_entry(self.entry) {
             Err(e) => {
-                eprint!("{}", e);
+                eprintln!("Error: Could not read entry {}: {e}", self.entry);
                 exit(1)
             }
-            Ok(file) => file,
+            Ok(vag) => vag.decode(),
         };
+        eprintln!(
+            "Streaming entry {}: {} samples, {} Hz, mono, 16 bits little endian",
+            self.entry,
+            audio.frame_count(),
+            audio.sample_rate
+        );
 
-        println!("Successfully extracted data from PPHD8File!");
-        if self.verbose {
-            println!("Result:");
-            println!("{}", file);
+        let mut stdout = BufWriter::new(io::stdout().lock());
+        let result = self
+            .write_audio(&mut stdout, &audio)
+            .and_then(|_| stdout.flush());
+        match result {
+            // The reader is gone, like when piping into `head`. Nothing else to do.
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+            Err(e) => {
+                eprintln!("Error: Could not write to stdout: {e}");
+                exit(1)
+            }
+            Ok(_) => {}
         }
+    }
 
-        file
+    fn write_audio<W: Write>(&self, writer: &mut W, audio: &DecodedAudio) -> io::Result<()> {
+        match self.format {
+            StreamFormat::Raw => {
+                for sample in &audio.samples {
+                    writer.write_all(&sample.to_le_bytes())?;
+                }
+                Ok(())
+            }
+            StreamFormat::Wav => wav::write_wav(writer, audio),
+        }
     }
+}
 
-    fn extract_vag_files(&self, file: &PPHD8FileData) -> Vec<VAGFile> {
-        println!("Extracting VAG file content...");
-        let vags = match file.get_vag_files() {
-            Err(e) => {
-                eprint!("{}", e);
-                exit(1);
+impl InspectVagArgs {
+    /// Runs the command. Files that can't be parsed are reported and skipped.
+    fn run(&self) {
+        check_file_exists(&self.input);
+
+        for vag_filepath in find_files(&self.input, "vag") {
+            println!("{}:", vag_filepath.display());
+            match VAGFile::parse_from_file(&vag_filepath) {
+                Err(e) => eprintln!("\tCould not parse VAG file. Error: {e}"),
+                Ok(vag) => {
+                    for line in vag.to_string().lines() {
+                        println!("\t{line}");
+                    }
+                }
             }
-            Ok(vags) => vags,
-        };
-        println!("VAG Files successfully parsed!");
+        }
+    }
+}
 
-        vags
+impl ConvertVagArgs {
+    /// Checks if the arguments are consistent.
+    /// If not, handle errors and exit the process
+    fn check(&self) {
+        check_file_exists(&self.input);
+        check_file_exists(&self.target_dir);
+        check_file_is_dir(&self.target_dir);
     }
 
-    fn save_vag_files(&self, vags: &Vec<VAGFile>) {
-        println!("Saving VAG files...");
+    /// Runs the command
+    fn run(&self) {
+        let vag_filepaths = find_files(&self.input, "vag");
+        println!("Converting {} VAG files...", vag_filepaths.len());
 
-        let errors: Vec<(usize, std::io::Error)> = vags
+        let errors: Vec<(&PathBuf, ParseError)> = vag_filepaths
             .par_iter()
-            .enumerate()
-            .map(|(i, vag)| {
-                let output_vag_filepath = self.target_dir.join(format!("extracted_{i}.vag"));
-                let output_vag_filepath = output_vag_filepath.as_path();
-                println!("Saving file {i} to {}...", output_vag_filepath.display());
-                (i, vag.write_to_file(output_vag_filepath))
+            .map(|vag_filepath| {
+                let extension = self.format.extension();
+                let stem = vag_filepath.file_stem().unwrap_or_default();
+                let output_filepath = self
+                    .target_dir
+                    .join(format!("{}.{extension}", stem.to_string_lossy()));
+                let result = VAGFile::parse_from_file(vag_filepath).and_then(|vag| {
+                    let vag = match self.vag_version {
+                        Some(version) => vag.with_version(version),
+                        None => vag,
+                    };
+                    println!("Saving file {}...", output_filepath.display());
+                    Ok(export_vag_file(&vag, &output_filepath, self.format, None)?)
+                });
+                (vag_filepath, result)
             })
-            .filter_map(|(i, output)| match output {
-                Err(e) => Some((i, e)),
+            .filter_map(|(vag_filepath, output)| match output {
+                Err(e) => Some((vag_filepath, e)),
                 _ => None,
             })
             .collect();
 
         if !errors.is_empty() {
-            eprintln!("Some files could not be extracted:");
-            for (i, error) in errors {
-                eprintln!("VAG File {i} could not be extracted. Error: {error}");
+            eprintln!("Some files could not be converted:");
+            for (vag_filepath, error) in errors {
+                eprintln!(
+                    "VAG File {} could not be converted. Error: {error}",
+                    vag_filepath.display()
+                );
             }
         } else {
-            println!("All files successfully extracted!");
+            println!("All files successfully converted!");
         }
     }
+}
+
+impl ImportWavArgs {
+    /// Checks if the arguments are consistent.
+    /// If not, handle errors and exit the process
+    fn check(&self) {
+        check_file_exists(&self.input);
+        check_file_exists(&self.target_dir);
+        check_file_is_dir(&self.target_dir);
+
+        check_sample_rate(self.sample_rate);
+    }
+
+    /// Runs the command
+    fn run(&self) {
+        let wav_filepaths = find_files(&self.input, "wav");
+        println!("Importing {} WAV files...", wav_filepaths.len());
+
+        let errors: Vec<(&PathBuf, ParseError)> = wav_filepaths
+            .par_iter()
+            .map(|wav_filepath| {
+                let stem = wav_filepath.file_stem().unwrap_or_default();
+                let stem = stem.to_string_lossy();
+                let output_filepath = self.target_dir.join(format!("{stem}.vag"));
+                let result =
+                    VAGFile::from_wav_file(wav_filepath, self.sample_rate).and_then(|vag| {
+                        let vag = vag.with_name(&stem).with_version(self.vag_version);
+                        println!("Saving file {}...", output_filepath.display());
+                        Ok(vag.write_to_file(&output_filepath)?)
+                    });
+                (wav_filepath, result)
+            })
+            .filter_map(|(wav_filepath, output)| match output {
+                Err(e) => Some((wav_filepath, e)),
+                _ => None,
+            })
+            .collect();
 
-    fn check_file_exists(path: &Path) {
-        if path.exists() {
-            // nothing to do
-            return;
+        if !errors.is_empty() {
+            eprintln!("Some files could not be imported:");
+            for (wav_filepath, error) in errors {
+                eprintln!(
+                    "WAV File {} could not be imported. Error: {error}",
+                    wav_filepath.display()
+                );
+            }
+        } else {
+            println!("All files successfully imported!");
         }
+    }
+}
 
-        eprintln!("Error: File '{}' does not exists", path.display());
-        exit(1);
+impl ReplaceEntryArgs {
+    /// Checks if the arguments are consistent.
+    /// If not, handle errors and exit the process
+    fn check(&self) {
+        check_file_exists(&self.pphd8_file);
+        check_file_exists(&self.input);
+        check_not_same_file(&self.pphd8_file, &self.output_file);
+
+        check_sample_rate(self.sample_rate);
+    }
+
+    /// Runs the command
+    fn run(&self) {
+        let file = parse_pphd8(&self.pphd8_file);
+        let vag = match self.read_input() {
+            Err(e) => {
+                eprintln!("Error: Could not read '{}': {e}", self.input.display());
+                exit(1)
+            }
+            Ok(vag) => vag,
+        };
+
+        println!(
+            "Replacing entry {} with {} ({} Hz, {} bytes)...",
+            self.entry,
+            self.input.display(),
+            vag.frequency(),
+            vag.body().len()
+        );
+        if let Err(e) = file.replace_entry_to_file(self.entry, &vag, &self.output_file) {
+            eprintln!("Error: Could not replace entry {}: {e}", self.entry);
+            exit(1)
+        }
+        println!("Saved new PPHD8 file to {}", self.output_file.display());
+    }
+
+    /// Read the input as a WAV file if it has a RIFF signature, or as a VAG file otherwise
+    fn read_input(&self) -> Result<VAGFile<'static>, ParseError> {
+        let bytes = fs::read(&self.input)?;
+        if bytes.starts_with(b"RIFF") {
+            VAGFile::from_wav(&bytes, self.sample_rate)
+        } else {
+            Ok(VAGFile::parse(&bytes)?.into_owned())
+        }
+    }
+}
+
+impl EditArgs {
+    /// Checks if the arguments are consistent.
+    /// If not, handle errors and exit the process
+    fn check(&self) {
+        check_file_exists(&self.pphd8_file);
+        check_not_same_file(&self.pphd8_file, &self.output_file);
+
+        if let Some(index) = self
+            .nulls
+            .iter()
+            .find(|index| self.removals.contains(index))
+        {
+            eprintln!("Error: Entry {index} can't be both nulled and removed");
+            exit(1);
+        }
+    }
+
+    /// Runs the command
+    fn run(&self) {
+        let file = parse_pphd8(&self.pphd8_file);
+
+        // Frequencies and null entries are only words of the index, so the file is patched.
+        // Moving or removing slots needs the whole file to be written again.
+        self.check_entries(&file);
+        if self.order.is_none() && self.removals.is_empty() {
+            self.patch(&file)
+        } else {
+            self.rebuild(&file)
+        }
+    }
+
+    /// Exit the process if any of the edited entries is not in the file, or the new order
+    /// doesn't have all of them, before changing anything
+    fn check_entries(&self, file: &PPHD8FileData) {
+        let indices = self
+            .frequencies
+            .iter()
+            .map(|(index, _)| index)
+            .chain(&self.nulls)
+            .chain(&self.removals);
+        for &index in indices {
+            if index >= file.n_files() {
+                let error = ParseError::EntryNotFound {
+                    entry_index: index,
+                    n_files: file.n_files(),
+                };
+                eprintln!("Error: Could not edit the file: {error}");
+                exit(1)
+            }
+        }
+
+        if let Some(order) = &self.order {
+            let mut sorted = order.clone();
+            sorted.sort_unstable();
+            if !sorted.iter().copied().eq(0..file.n_files()) {
+                let error = ParseError::InvalidEntryOrder {
+                    entry_count: file.n_files(),
+                };
+                eprintln!("Error: Could not edit the file: {error}");
+                exit(1)
+            }
+        }
+    }
+
+    /// Save a copy of the file with only the words of the changed slots patched
+    fn patch(&self, file: &PPHD8FileData) {
+        for &(index, frequency) in &self.frequencies {
+            println!("Setting the frequency of entry {index} to {frequency} Hz");
+        }
+        let mut nulls = self.nulls.clone();
+        nulls.sort_unstable();
+        nulls.dedup();
+        for index in &nulls {
+            println!("Nulling entry {index}");
+        }
+
+        if let Err(e) = file.edit_entries_to_file(&self.frequencies, &nulls, &self.output_file) {
+            eprintln!("Error: Could not edit the file: {e}");
+            exit(1)
+        }
+        println!(
+            "Saved new PPHD8 file with {} entries to {}",
+            file.n_files().saturating_sub(nulls.len()),
+            self.output_file.display()
+        );
+    }
+
+    /// Save the file written again with all the changes, keeping its layout where possible
+    fn rebuild(&self, file: &PPHD8FileData) {
+        let mut writer = match PPHD8Writer::from_pphd8(file) {
+            Err(e) => {
+                eprintln!("Error: Could not read the entries: {e}");
+                exit(1)
+            }
+            Ok(writer) => writer,
+        };
+
+        if let Err(e) = self.apply(&mut writer) {
+            eprintln!("Error: Could not edit the file: {e}");
+            exit(1)
+        }
+
+        if let Err(e) = writer.write_to_file(&self.output_file) {
+            eprintln!(
+                "Error: Could not save '{}': {e}",
+                self.output_file.display()
+            );
+            exit(1)
+        }
+        println!(
+            "Saved new PPHD8 file with {} entries to {}",
+            writer.entry_count(),
+            self.output_file.display()
+        );
     }
 
-    fn check_file_is_dir(path: &Path) {
-        if path.is_dir() {
-            // nothing to do
-            return;
+    /// Apply the changes: frequencies, then the new order, then nulls and removals
+    fn apply(&self, writer: &mut PPHD8Writer) -> Result<(), ParseError> {
+        for &(index, frequency) in &self.frequencies {
+            println!("Setting the frequency of entry {index} to {frequency} Hz");
+            writer.set_frequency(index, frequency)?;
+        }
+
+        // Where each original entry is after reordering
+        let mut positions: Vec<usize> = (0..writer.entry_count()).collect();
+        if let Some(order) = &self.order {
+            println!("Reordering entries");
+            writer.reorder_entries(order)?;
+            for (position, &index) in order.iter().enumerate() {
+                positions[index] = position;
+            }
+        }
+
+        let mut changes: Vec<(usize, bool)> = self
+            .nulls
+            .iter()
+            .map(|&index| (index, false))
+            .chain(self.removals.iter().map(|&index| (index, true)))
+            .collect();
+        changes.sort_unstable();
+        changes.dedup();
+        for &(index, _) in &changes {
+            if index >= positions.len() {
+                return Err(ParseError::EntryNotFound {
+                    entry_index: index,
+                    n_files: positions.len(),
+                });
+            }
+        }
+
+        // From the last position to the first, so the positions left don't change
+        changes.sort_by_key(|&(index, _)| std::cmp::Reverse(positions[index]));
+        for (index, remove) in changes {
+            if remove {
+                println!("Removing entry {index}");
+                writer.remove_entry(positions[index])?;
+            } else {
+                println!("Nulling entry {index}");
+                writer.null_entry(positions[index])?;
+            }
         }
 
-        eprintln!("Error: File '{}' is not a directory", path.display());
+        Ok(())
+    }
+}
+
+impl MergeArgs {
+    /// Checks if the arguments are consistent.
+    /// If not, handle errors and exit the process
+    fn check(&self) {
+        for pphd8_file in &self.pphd8_files {
+            check_file_exists(pphd8_file);
+            check_not_same_file(pphd8_file, &self.output_file);
+        }
+    }
+
+    /// Runs the command
+    fn run(&self) {
+        let banks: Vec<PPHD8FileData> = self
+            .pphd8_files
+            .iter()
+            .map(|pphd8_file| parse_pphd8(pphd8_file))
+            .collect();
+        let header = if self.default_header {
+            MergeHeader::Default
+        } else {
+            MergeHeader::First
+        };
+
+        let (merged, mapping) = match merge_banks(&banks, header) {
+            Err(e) => {
+                eprintln!("Error: Could not merge the files: {e}");
+                exit(1)
+            }
+            Ok(merged) => merged,
+        };
+
+        if let Err(e) = merged.write_to_file(&self.output_file) {
+            eprintln!(
+                "Error: Could not save '{}': {e}",
+                self.output_file.display()
+            );
+            exit(1)
+        }
+
+        println!("New index of each entry:");
+        for entry in &mapping {
+            println!(
+                "\t{} {} -> {}",
+                self.pphd8_files[entry.bank].display(),
+                entry.index,
+                entry.new_index
+            );
+        }
+
+        if let Some(mapping_file) = &self.mapping {
+            if let Err(e) = self.write_mapping(mapping_file, &mapping) {
+                eprintln!(
+                    "Error: Could not save the mapping to '{}': {e}",
+                    mapping_file.display()
+                );
+                exit(1)
+            }
+        }
+
+        println!(
+            "Saved merged PPHD8 file with {} entries to {}",
+            merged.entry_count(),
+            self.output_file.display()
+        );
+    }
+
+    /// Save the mapping as CSV, with the path of each bank as it was given
+    fn write_mapping(&self, mapping_file: &Path, mapping: &[MergedEntry]) -> io::Result<()> {
+        let mut writer = BufWriter::new(fs::File::create(mapping_file)?);
+        writeln!(writer, "bank,index,new_index")?;
+        for entry in mapping {
+            let bank = self.pphd8_files[entry.bank].display().to_string();
+            // Quote the path, it may have commas
+            writeln!(
+                writer,
+                "\"{}\",{},{}",
+                bank.replace('"', "\"\""),
+                entry.index,
+                entry.new_index
+            )?;
+        }
+        writer.flush()
+    }
+}
+
+/// Parse a frequency change, written as INDEX=HZ
+fn parse_frequency_edit(value: &str) -> Result<(usize, u32), String> {
+    let (index, frequency) = value
+        .split_once('=')
+        .ok_or_else(|| format!("'{value}' should be INDEX=HZ"))?;
+    let index = index
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid entry index '{index}': {e}"))?;
+    let frequency = frequency
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid frequency '{frequency}': {e}"))?;
+    Ok((index, frequency))
+}
+
+/// List the files to process: `input` itself if it's a file, or the files with the given
+/// extension inside it if it's a directory. Exits the process if the directory can't be read.
+fn find_files(input: &Path, extension: &str) -> Vec<PathBuf> {
+    if !input.is_dir() {
+        return vec![input.to_path_buf()];
+    }
+
+    let entries = match input.read_dir() {
+        Err(e) => {
+            eprintln!("Error: Could not read directory '{}': {e}", input.display());
+            exit(1)
+        }
+        Ok(entries) => entries,
+    };
+
+    let mut filepaths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|file_extension| file_extension.eq_ignore_ascii_case(extension))
+        })
+        .collect();
+    filepaths.sort();
+    filepaths
+}
+
+/// Parse a PPHD8 file, exiting the process if it's not valid
+fn parse_pphd8(pphd8_file: &Path) -> PPHD8FileData {
+    match PPHD8FileData::parse_from_file(pphd8_file) {
+        Err(e) => {
+            eprint!("{}", e);
+            exit(1)
+        }
+        Ok(file) => file,
+    }
+}
+
+fn check_file_exists(path: &Path) {
+    if path.exists() {
+        // nothing to do
+        return;
+    }
+
+    eprintln!("Error: File '{}' does not exists", path.display());
+    exit(1);
+}
+
+/// Exit the process if `output_file` is `pphd8_file`, which is still being read
+fn check_not_same_file(pphd8_file: &Path, output_file: &Path) {
+    let same_file = match (pphd8_file.canonicalize(), output_file.canonicalize()) {
+        (Ok(pphd8_file), Ok(output_file)) => pphd8_file == output_file,
+        _ => false,
+    };
+    if same_file {
+        eprintln!("Error: The output file can't be the PPHD8 file being modified");
         exit(1);
     }
 }
 
+/// Exit the process if the sample rate to convert to is 0 or too high to be real
+fn check_sample_rate(sample_rate: Option<u32>) {
+    if let Some(sample_rate) = sample_rate {
+        if !(1..=MAX_SAMPLE_RATE).contains(&sample_rate) {
+            eprintln!("Error: The sample rate must be between 1 and {MAX_SAMPLE_RATE} Hz");
+            exit(1);
+        }
+    }
+}
+
+fn check_file_is_dir(path: &Path) {
+    if path.is_dir() {
+        // nothing to do
+        return;
+    }
+
+    eprintln!("Error: File '{}' is not a directory", path.display());
+    exit(1);
+}
+
 fn main() {
     let cli = Cli::parse();
-    cli.check();
-    cli.run();
+    match (cli.command, cli.extract) {
+        (Some(Command::Stream(stream)), _) => stream.run(),
+        (Some(Command::InspectVag(inspect)), _) => inspect.run(),
+        (Some(Command::ConvertVag(convert)), _) => {
+            convert.check();
+            convert.run();
+        }
+        (Some(Command::ImportWav(import)), _) => {
+            import.check();
+            import.run();
+        }
+        (Some(Command::ReplaceEntry(replace)), _) => {
+            replace.check();
+            replace.run();
+        }
+        (Some(Command::Edit(edit)), _) => {
+            edit.check();
+            edit.run();
+        }
+        (Some(Command::Merge(merge)), _) => {
+            merge.check();
+            merge.run();
+        }
+        (None, Some(extract)) => {
+            extract.check();
+            extract.run();
+        }
+        // clap asks for the extraction arguments when there is no command
+        (None, None) => unreachable!(),
+    }
 }
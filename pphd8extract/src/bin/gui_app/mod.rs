@@ -8,13 +8,14 @@ use eframe::{
     emath::Align,
     epaint::{vec2, Color32, Stroke},
 };
-use pphd8extract::pphd8parser::PPHD8FileData;
+use pphd8extract::export::{export_vag_file, EntrySource, ExportFormat};
+use pphd8extract::pphd8parser::{PPHD8FileData, PPHD8Variant};
 use scc::Queue;
 
 // Rust imports
 use rayon::{
     self,
-    iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator},
+    iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator},
 };
 use std::{
     fmt::Display,
@@ -32,6 +33,7 @@ pub struct App {
     are_files_hovering: bool,
     state: AppState,
     output_dir: Option<PathBuf>,
+    output_format: ExportFormat,
     processing_work: Option<WorkManager>,
 }
 
@@ -108,6 +110,9 @@ impl App {
         // Selection of output dir
         self.draw_output_dir_field(ui);
 
+        // Selection of output format
+        self.draw_output_format_field(ui);
+
         ui.add_space(16.0);
 
         // Extraction button
@@ -160,16 +165,13 @@ impl App {
         let path_buf = file.path.as_ref().unwrap();
         let file_path = path_buf.as_path();
 
-        // Check that this file is a valid pphd
-        if let Some(extension) = file_path.extension() {
-            if extension != "pphd8" {
-                return Err(FileErrors::NotAValidPPHD8);
-            }
-        } else {
-            return Err(FileErrors::NotAValidPPHD8);
+        // Check that this file is a valid pphd by looking at its signature, not its extension
+        match pphd8parser::detect_file(file_path) {
+            Ok(PPHD8Variant::V8) => Ok(()),
+            Ok(PPHD8Variant::Unknown(version)) => Err(FileErrors::UnsupportedVersion(version)),
+            Err(pphd8parser::ParseError::NotPPHD8) => Err(FileErrors::NotAValidPPHD8),
+            Err(_) => Err(FileErrors::CouldNotRead),
         }
-
-        Ok(())
     }
 
     fn draw_file_dropping_area(&self, ui: &mut egui::Ui) {
@@ -291,6 +293,25 @@ impl App {
         }
     }
 
+    fn draw_output_format_field(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label(RichText::new("Output Format:").size(18.0));
+            ui.add_space(8.0);
+
+            for format in ExportFormat::ALL {
+                ui.radio_value(&mut self.output_format, format, format.to_string())
+                    .on_hover_text(match format {
+                        ExportFormat::Vag => "Save the raw VAG files, as stored in the PPHD8 file",
+                        ExportFormat::Wav => "Decode the VAG files and save them as WAV files",
+                        ExportFormat::Aiff => "Decode the VAG files and save them as AIFF files",
+                        ExportFormat::Flac => {
+                            "Decode the VAG files and save them as compressed FLAC files"
+                        }
+                    });
+            }
+        });
+    }
+
     fn draw_start_button(&mut self, ui: &mut egui::Ui) {
         ui.allocate_ui(ui.available_size() * egui::vec2(1.0, 0.25), |ui| {
             ui.horizontal_centered(|ui| {
@@ -368,7 +389,7 @@ impl App {
         let output_dir = self.get_output_dir();
 
         if let Some(w) = self.processing_work.as_mut() {
-            w.start_work(output_dir);
+            w.start_work(output_dir, self.output_format);
         }
 
         self.state = AppState::ProcessingFiles;
@@ -507,10 +528,10 @@ impl WorkManager {
         }
     }
 
-    fn start_work(&mut self, output_dir: PathBuf) {
+    fn start_work(&mut self, output_dir: PathBuf, output_format: ExportFormat) {
         let work = self.work.clone();
         self.processing_thread_handle = Some(thread::spawn(move || {
-            work.do_work(output_dir);
+            work.do_work(output_dir, output_format);
         }));
     }
 
@@ -553,7 +574,7 @@ impl WorkManager {
 }
 
 impl Work {
-    fn do_work(&self, output_dir: PathBuf) {
+    fn do_work(&self, output_dir: PathBuf, output_format: ExportFormat) {
         let mut files_to_process = vec![];
         let output_dir = output_dir.as_path();
 
@@ -566,6 +587,10 @@ impl Work {
             .map(|file| {
                 // parse pphd file
                 let pphd_filepath = file.as_path();
+                let bank_name = pphd_filepath
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy())
+                    .unwrap_or_default();
                 let pphd8_file = match PPHD8FileData::parse_from_file(pphd_filepath) {
                     Err(e) => {
                         self.error_files.push(((***file).clone(), e));
@@ -574,29 +599,32 @@ impl Work {
                     Ok(file) => file,
                 };
 
-                // extract vag files
-                let vags = match pphd8_file.get_vag_files() {
-                    Err(e) => {
-                        self.error_files.push(((***file).clone(), e));
-                        return;
-                    }
-                    Ok(vags) => vags,
-                };
-
-                // save vag files
-                vags.par_iter()
-                    .enumerate()
-                    .map(|(i, file)| {
+                // extract and save vag files one by one, a broken entry only affects its own file
+                (0..pphd8_file.n_files())
+                    .into_par_iter()
+                    .map(|i| {
                         let mut filename = pphd_filepath
                             .file_name()
                             .unwrap()
                             .to_str()
                             .unwrap()
                             .to_string();
-                        filename.push_str(format!("extracted_{i}.vag").as_str());
+                        let extension = output_format.extension();
+                        filename.push_str(format!("extracted_{i}.{extension}").as_str());
                         let filepath = output_dir.join(filename);
                         // TODO handle this error as well
-                        let result = file.write_to_file(filepath.as_path());
+                        let source = EntrySource {
+                            bank_name: &bank_name,
+                            entry_index: i,
+                        };
+                        let result = pphd8_file.read_entry(i).and_then(|vag| {
+                            Ok(export_vag_file(
+                                &vag,
+                                filepath.as_path(),
+                                output_format,
+                                Some(&source),
+                            )?)
+                        });
                         self.generated_files.push((filepath, result.is_ok()));
                     })
                     .collect::<Vec<()>>();
@@ -611,6 +639,8 @@ impl Work {
 #[derive(Debug)]
 enum FileErrors {
     NotAValidPPHD8,
+    UnsupportedVersion(u32),
+    CouldNotRead,
 }
 
 impl Default for App {
@@ -620,6 +650,7 @@ impl Default for App {
             are_files_hovering: false,
             state: AppState::WaitingForFiles,
             output_dir: None,
+            output_format: ExportFormat::default(),
             processing_work: None,
         }
     }
@@ -629,6 +660,10 @@ impl Display for FileErrors {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             FileErrors::NotAValidPPHD8 => write!(f, "Not a valid pphd8 file"),
+            FileErrors::UnsupportedVersion(version) => {
+                write!(f, "Unsupported pphd8 version: {version}")
+            }
+            FileErrors::CouldNotRead => write!(f, "Could not read file"),
         }
     }
 }
@@ -0,0 +1,105 @@
+// Saving extracted VAG files in the formats supported by the CLI and GUI
+
+use std::fmt::Display;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::pphd8parser::VAGFile;
+
+/// File format used when saving extracted entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    /// The raw ADPCM body with a VAGp header, as stored in the bank
+    #[default]
+    Vag,
+    /// Decoded 16 bits PCM in a RIFF/WAVE file
+    Wav,
+    /// Decoded 16 bits PCM in an AIFF file
+    Aiff,
+    /// Decoded 16 bits PCM, losslessly compressed in a FLAC file
+    Flac,
+}
+
+impl ExportFormat {
+    /// All the formats, in the order they should be shown to users
+    pub const ALL: [ExportFormat; 4] = [
+        ExportFormat::Vag,
+        ExportFormat::Wav,
+        ExportFormat::Aiff,
+        ExportFormat::Flac,
+    ];
+
+    /// File extension for this format, without the dot
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Vag => "vag",
+            ExportFormat::Wav => "wav",
+            ExportFormat::Aiff => "aiff",
+            ExportFormat::Flac => "flac",
+        }
+    }
+}
+
+/// Where an exported VAG file comes from, saved as metadata by the formats that support it
+#[derive(Debug, Clone, Copy)]
+pub struct EntrySource<'a> {
+    /// Name of the PPHD8 file, usually its file name without extension
+    pub bank_name: &'a str,
+    /// Index of the entry inside the bank
+    pub entry_index: usize,
+}
+
+/// Save a VAG file to `filepath` using the given format.
+///
+/// `source` is only needed for files that come from a bank, standalone VAG files have none.
+pub fn export_vag_file(
+    vag: &VAGFile,
+    filepath: &Path,
+    format: ExportFormat,
+    source: Option<&EntrySource>,
+) -> io::Result<()> {
+    match format {
+        ExportFormat::Vag => vag.write_to_file(filepath),
+        ExportFormat::Wav => vag.write_wav_file(filepath),
+        ExportFormat::Aiff => vag.write_aiff_file(filepath),
+        ExportFormat::Flac => {
+            let comments = match source {
+                Some(source) => vec![
+                    ("BANKNAME", source.bank_name.to_string()),
+                    ("ENTRYINDEX", source.entry_index.to_string()),
+                ],
+                None => vec![],
+            };
+            vag.write_flac_file(filepath, &comments)
+        }
+    }
+}
+
+impl Display for ExportFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportFormat::Vag => write!(f, "VAG"),
+            ExportFormat::Wav => write!(f, "WAV"),
+            ExportFormat::Aiff => write!(f, "AIFF"),
+            ExportFormat::Flac => write!(f, "FLAC"),
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ExportFormat::ALL
+            .into_iter()
+            .find(|format| format.extension().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let valid: Vec<&str> = ExportFormat::ALL.iter().map(|f| f.extension()).collect();
+                format!(
+                    "Unknown format '{s}', valid formats are: {}",
+                    valid.join(", ")
+                )
+            })
+    }
+}
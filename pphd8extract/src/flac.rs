@@ -0,0 +1,489 @@
+// FLAC encoder for decoded VAG files.
+//
+// A small lossless encoder, it only supports what we need for decoded PS-ADPCM: 16 bits
+// samples, each channel encoded on its own. Each block is saved as a constant, verbatim or
+// fixed predictor subframe, whatever is smaller, with Rice coded residuals.
+
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::adpcm::{DecodedAudio, BITS_PER_SAMPLE, MAX_SAMPLE_RATE};
+
+/// Samples per frame, the usual block size for 44.1kHz audio
+const BLOCK_SIZE: usize = 4096;
+/// Highest order of the fixed predictors, from the FLAC format
+const MAX_FIXED_ORDER: usize = 4;
+/// Highest partition order tried when splitting residuals for Rice coding
+const MAX_PARTITION_ORDER: u32 = 6;
+/// Highest Rice parameter that fits the 4 bits field without escaping
+const MAX_RICE_PARAMETER: u32 = 14;
+/// Highest number of channels of a FLAC stream
+const MAX_CHANNELS: u16 = 8;
+/// Metadata block types
+const BLOCK_TYPE_STREAMINFO: u8 = 0;
+const BLOCK_TYPE_VORBIS_COMMENT: u8 = 4;
+/// Written as the vendor string of the Vorbis comment block
+const VENDOR: &str = concat!("pphd8extract ", env!("CARGO_PKG_VERSION"));
+
+/// Write decoded audio as a 16 bits FLAC file.
+///
+/// `comments` are stored as Vorbis comments, along with the sample rate and the loop points
+/// (`LOOPSTART` and `LOOPLENGTH`) when the audio has a loop region.
+pub fn write_flac<W: Write>(
+    writer: &mut W,
+    audio: &DecodedAudio,
+    comments: &[(&str, String)],
+) -> io::Result<()> {
+    // Sample rates and sample counts have 20 and 36 bits in the stream info
+    if audio.sample_rate == 0 || audio.sample_rate > MAX_SAMPLE_RATE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid sample rate for a FLAC file: {}", audio.sample_rate),
+        ));
+    }
+    if audio.channels == 0 || audio.channels > MAX_CHANNELS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid number of channels for a FLAC file: {}",
+                audio.channels
+            ),
+        ));
+    }
+    if audio.frame_count() as u64 >= 1 << 36 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Too much audio for a FLAC file",
+        ));
+    }
+
+    writer.write_all(b"fLaC")?;
+    write_metadata_block(writer, BLOCK_TYPE_STREAMINFO, false, &stream_info(audio))?;
+    write_metadata_block(
+        writer,
+        BLOCK_TYPE_VORBIS_COMMENT,
+        true,
+        &vorbis_comment(audio, comments),
+    )?;
+
+    // Only whole frames, a missing sample can't be encoded
+    let channels = audio.channels as usize;
+    let samples = &audio.samples[..audio.frame_count() * channels];
+    for (frame_number, block) in samples.chunks(BLOCK_SIZE * channels).enumerate() {
+        writer.write_all(&encode_frame(frame_number as u32, block, channels))?;
+    }
+
+    Ok(())
+}
+
+/// Write decoded audio to a FLAC file in disk
+pub fn write_flac_file(
+    filepath: &Path,
+    audio: &DecodedAudio,
+    comments: &[(&str, String)],
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(fs::File::create(filepath)?);
+    write_flac(&mut writer, audio, comments)?;
+    writer.flush()
+}
+
+fn write_metadata_block<W: Write>(
+    writer: &mut W,
+    block_type: u8,
+    is_last: bool,
+    data: &[u8],
+) -> io::Result<()> {
+    let length = u32::try_from(data.len())
+        .ok()
+        .filter(|length| *length < 1 << 24)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Metadata is too long"))?;
+
+    // Last block flag, 7 bits of type and 24 bits of length
+    writer.write_all(&[((is_last as u8) << 7) | block_type])?;
+    writer.write_all(&length.to_be_bytes()[1..])?;
+    writer.write_all(data)
+}
+
+/// STREAMINFO block. Frame sizes and the MD5 signature are left as unknown (zero).
+fn stream_info(audio: &DecodedAudio) -> Vec<u8> {
+    let mut bits = BitWriter::new();
+    let block_size = BLOCK_SIZE.min(audio.frame_count()).max(16) as u64;
+    bits.write(16, block_size); // min block size
+    bits.write(16, block_size); // max block size
+    bits.write(24, 0); // min frame size
+    bits.write(24, 0); // max frame size
+    bits.write(20, audio.sample_rate as u64);
+    bits.write(3, (audio.channels - 1) as u64);
+    bits.write(5, (BITS_PER_SAMPLE - 1) as u64);
+    bits.write(36, audio.frame_count() as u64);
+    bits.write(64, 0); // MD5
+    bits.write(64, 0);
+    bits.into_bytes()
+}
+
+/// VORBIS_COMMENT block, the only part of FLAC in little endian
+fn vorbis_comment(audio: &DecodedAudio, comments: &[(&str, String)]) -> Vec<u8> {
+    let mut all_comments = vec![format!("SAMPLERATE={}", audio.sample_rate)];
+    all_comments.extend(comments.iter().map(|(key, value)| format!("{key}={value}")));
+    if let Some(loop_region) = audio.loop_region {
+        all_comments.push(format!("LOOPSTART={}", loop_region.start));
+        all_comments.push(format!(
+            "LOOPLENGTH={}",
+            loop_region.end.saturating_sub(loop_region.start)
+        ));
+    }
+
+    let mut data = vec![];
+    data.extend_from_slice(&(VENDOR.len() as u32).to_le_bytes());
+    data.extend_from_slice(VENDOR.as_bytes());
+    data.extend_from_slice(&(all_comments.len() as u32).to_le_bytes());
+    for comment in all_comments {
+        data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        data.extend_from_slice(comment.as_bytes());
+    }
+    data
+}
+
+/// Encode a frame with a subframe for each channel, `block` has the samples interleaved
+fn encode_frame(frame_number: u32, block: &[i16], channels: usize) -> Vec<u8> {
+    let mut bits = BitWriter::new();
+    let block_size = block.len() / channels;
+
+    // Frame header
+    bits.write(14, 0b11_1111_1111_1110); // sync code
+    bits.write(1, 0); // reserved
+    bits.write(1, 0); // fixed block size
+    bits.write(4, 0b0111); // block size - 1 stored as 16 bits at the end of the header
+    bits.write(4, 0b0000); // sample rate from the stream info
+    bits.write(4, (channels - 1) as u64); // independent channels
+    bits.write(3, 0b100); // 16 bits per sample
+    bits.write(1, 0); // reserved
+    write_utf8_number(&mut bits, frame_number);
+    bits.write(16, (block_size - 1) as u64);
+    let crc = crc8(bits.bytes());
+    bits.write(8, crc as u64);
+
+    for channel in 0..channels {
+        let samples: Vec<i32> = block
+            .iter()
+            .skip(channel)
+            .step_by(channels)
+            .map(|sample| *sample as i32)
+            .collect();
+        write_subframe(&mut bits, &samples);
+    }
+
+    // Frame footer, byte aligned
+    bits.align();
+    let crc = crc16(bits.bytes());
+    bits.write(16, crc as u64);
+    bits.into_bytes()
+}
+
+/// Write the subframe that takes the least space for these samples
+fn write_subframe(bits: &mut BitWriter, samples: &[i32]) {
+    if samples.iter().all(|sample| *sample == samples[0]) {
+        bits.write(8, 0b0000_0000);
+        bits.write_signed(BITS_PER_SAMPLE.into(), samples[0]);
+        return;
+    }
+
+    let verbatim_size = samples.len() as u64 * BITS_PER_SAMPLE as u64;
+    let best_fixed = (0..=MAX_FIXED_ORDER.min(samples.len() - 1))
+        .map(|order| {
+            let residuals = fixed_residuals(samples, order);
+            let (partition_order, size) = best_partitioning(&residuals, order);
+            let size = size + order as u64 * BITS_PER_SAMPLE as u64;
+            (order, residuals, partition_order, size)
+        })
+        .min_by_key(|(_, _, _, size)| *size);
+
+    match best_fixed {
+        Some((order, residuals, partition_order, size)) if size < verbatim_size => {
+            bits.write(8, (0b0001_0000 | order << 1) as u64);
+            for sample in &samples[..order] {
+                bits.write_signed(BITS_PER_SAMPLE.into(), *sample);
+            }
+            write_residuals(bits, &residuals, order, partition_order);
+        }
+        _ => {
+            bits.write(8, 0b0000_0010);
+            for sample in samples {
+                bits.write_signed(BITS_PER_SAMPLE.into(), *sample);
+            }
+        }
+    }
+}
+
+/// Residuals of a fixed predictor, starting after the `order` warm up samples
+fn fixed_residuals(samples: &[i32], order: usize) -> Vec<i32> {
+    (order..samples.len())
+        .map(|i| {
+            let s = |back: usize| samples[i - back];
+            match order {
+                0 => s(0),
+                1 => s(0) - s(1),
+                2 => s(0) - 2 * s(1) + s(2),
+                3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+                _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+            }
+        })
+        .collect()
+}
+
+/// Partitions of the residuals for a partition order. The first partition is shorter, since
+/// the warm up samples count as part of it.
+fn partitions(residuals: &[i32], predictor_order: usize, partition_order: u32) -> Vec<&[i32]> {
+    let partition_len = (residuals.len() + predictor_order) >> partition_order;
+    let mut partitions = vec![&residuals[..partition_len - predictor_order]];
+    partitions.extend(residuals[partition_len - predictor_order..].chunks(partition_len));
+    partitions
+}
+
+/// Find the partition order that gives the smallest residual, and its size in bits
+fn best_partitioning(residuals: &[i32], predictor_order: usize) -> (u32, u64) {
+    let block_size = residuals.len() + predictor_order;
+
+    (0..=MAX_PARTITION_ORDER)
+        // The block must split evenly and the first partition can't be smaller than the warm up
+        .take_while(|partition_order| {
+            block_size.trailing_zeros() >= *partition_order
+                && (block_size >> partition_order) > predictor_order
+        })
+        .map(|partition_order| {
+            let size = partitions(residuals, predictor_order, partition_order)
+                .into_iter()
+                .map(|partition| {
+                    let parameter = best_rice_parameter(partition);
+                    4 + rice_size(partition, parameter)
+                })
+                .sum::<u64>();
+            (partition_order, 6 + size)
+        })
+        .min_by_key(|(_, size)| *size)
+        .unwrap_or((0, u64::MAX))
+}
+
+fn write_residuals(
+    bits: &mut BitWriter,
+    residuals: &[i32],
+    predictor_order: usize,
+    partition_order: u32,
+) {
+    bits.write(2, 0b00); // Rice coding with 4 bits parameters
+    bits.write(4, partition_order as u64);
+    for partition in partitions(residuals, predictor_order, partition_order) {
+        let parameter = best_rice_parameter(partition);
+        bits.write(4, parameter as u64);
+        for residual in partition {
+            let value = zigzag(*residual);
+            bits.write_unary(value >> parameter);
+            bits.write(parameter, value & ((1 << parameter) - 1));
+        }
+    }
+}
+
+fn best_rice_parameter(residuals: &[i32]) -> u32 {
+    (0..=MAX_RICE_PARAMETER)
+        .min_by_key(|parameter| rice_size(residuals, *parameter))
+        .unwrap_or(0)
+}
+
+/// Size in bits of the residuals Rice coded with this parameter
+fn rice_size(residuals: &[i32], parameter: u32) -> u64 {
+    residuals
+        .iter()
+        .map(|residual| (zigzag(*residual) >> parameter) + 1 + parameter as u64)
+        .sum()
+}
+
+/// Map signed numbers to unsigned ones, alternating: 0, -1, 1, -2, 2...
+fn zigzag(value: i32) -> u64 {
+    ((value << 1) ^ (value >> 31)) as u32 as u64
+}
+
+/// Frame numbers are written with the same variable length encoding as UTF-8 characters
+fn write_utf8_number(bits: &mut BitWriter, number: u32) {
+    if number < 0x80 {
+        bits.write(8, number as u64);
+        return;
+    }
+
+    // Each continuation byte holds 6 bits, and the first byte has less room as more follow
+    let mut continuation_bytes = 1;
+    while number >> (6 * continuation_bytes) >= 1 << (6 - continuation_bytes) {
+        continuation_bytes += 1;
+    }
+
+    let prefix = (0xFF00u32 >> (continuation_bytes + 1)) & 0xFF;
+    bits.write(8, (prefix | (number >> (6 * continuation_bytes))) as u64);
+    for i in (0..continuation_bytes).rev() {
+        bits.write(8, (0x80 | ((number >> (6 * i)) & 0x3F)) as u64);
+    }
+}
+
+/// CRC-8 of frame headers, polynomial x^8 + x^2 + x + 1
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// CRC-16 of whole frames, polynomial x^16 + x^15 + x^2 + 1
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Writes numbers MSB first with any number of bits
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits not yet written to `bytes`, in the lowest `n_bits` bits
+    buffer: u64,
+    n_bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            bytes: vec![],
+            buffer: 0,
+            n_bits: 0,
+        }
+    }
+
+    /// Write the lowest `n_bits` bits of `value`, up to 32 bits at a time
+    fn write(&mut self, n_bits: u32, value: u64) {
+        if n_bits > 32 {
+            self.write(n_bits - 32, value >> 32);
+            self.write(32, value & 0xFFFF_FFFF);
+            return;
+        }
+
+        self.buffer = (self.buffer << n_bits) | (value & ((1 << n_bits) - 1));
+        self.n_bits += n_bits;
+        while self.n_bits >= 8 {
+            self.n_bits -= 8;
+            self.bytes.push((self.buffer >> self.n_bits) as u8);
+        }
+    }
+
+    fn write_signed(&mut self, n_bits: u32, value: i32) {
+        self.write(n_bits, value as u32 as u64);
+    }
+
+    /// Write `value` zeros followed by a one
+    fn write_unary(&mut self, mut value: u64) {
+        while value >= 32 {
+            self.write(32, 0);
+            value -= 32;
+        }
+        self.write(value as u32 + 1, 1);
+    }
+
+    /// Pad with zeros up to the next byte
+    fn align(&mut self) {
+        if self.n_bits > 0 {
+            self.write(8 - self.n_bits, 0);
+        }
+    }
+
+    /// Bytes written so far, without the bits of an incomplete byte
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf8_number(number: u32) -> Vec<u8> {
+        let mut bits = BitWriter::new();
+        write_utf8_number(&mut bits, number);
+        bits.into_bytes()
+    }
+
+    #[test]
+    fn crc8_matches_the_check_value() {
+        assert_eq!(crc8(b""), 0x00);
+        assert_eq!(crc8(b"123456789"), 0xF4);
+    }
+
+    #[test]
+    fn crc16_matches_the_check_value() {
+        assert_eq!(crc16(b""), 0x0000);
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+    }
+
+    #[test]
+    fn utf8_numbers_are_encoded_like_utf8_characters() {
+        assert_eq!(utf8_number(0), [0x00]);
+        assert_eq!(utf8_number(0x7F), [0x7F]);
+        assert_eq!(utf8_number(0x80), [0xC2, 0x80]);
+        assert_eq!(utf8_number(0x7FF), [0xDF, 0xBF]);
+        assert_eq!(utf8_number(0x800), [0xE0, 0xA0, 0x80]);
+        assert_eq!(utf8_number(0xFFFF), [0xEF, 0xBF, 0xBF]);
+        assert_eq!(utf8_number(0x10000), [0xF0, 0x90, 0x80, 0x80]);
+        // Past the range of characters, frame numbers go up to 31 bits
+        assert_eq!(utf8_number(0x200000), [0xF8, 0x88, 0x80, 0x80, 0x80]);
+        assert_eq!(
+            utf8_number(0x7FFF_FFFF),
+            [0xFD, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF]
+        );
+
+        for character in ['a', 'é', '€', '😀'] {
+            let mut expected = [0u8; 4];
+            let expected = character.encode_utf8(&mut expected).as_bytes();
+            assert_eq!(utf8_number(character as u32), expected);
+        }
+    }
+
+    #[test]
+    fn constant_block_uses_a_constant_subframe() {
+        let frame = encode_frame(0, &[1000; 16], 1);
+        assert_eq!(
+            frame,
+            [
+                0xFF, 0xF8, 0x70, 0x08, 0x00, 0x00, 0x0F, 0x37, // header, 16 samples, CRC-8
+                0x00, 0x03, 0xE8, // constant subframe, 1000
+                0xD4, 0xAA, // CRC-16
+            ]
+        );
+    }
+
+    #[test]
+    fn ramp_uses_a_second_order_fixed_subframe() {
+        let ramp: Vec<i16> = (0..16).collect();
+        let frame = encode_frame(0, &ramp, 1);
+        assert_eq!(
+            frame,
+            [
+                0xFF, 0xF8, 0x70, 0x08, 0x00, 0x00, 0x0F, 0x37, // header, 16 samples, CRC-8
+                0x14, // fixed subframe, order 2
+                0x00, 0x00, 0x00, 0x01, // warm up samples, 0 and 1
+                0x00, 0x3F, 0xFF, // one partition with parameter 0, 14 residuals of 0
+                0xBB, 0xFB, // CRC-16
+            ]
+        );
+    }
+}
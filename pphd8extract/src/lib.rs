@@ -1 +1,12 @@
+// The parser has to handle untrusted files, mapping files to memory is the only unsafe operation
+#![deny(unsafe_code)]
+
+pub mod adpcm;
+pub mod aiff;
+pub mod export;
+pub mod flac;
 pub mod pphd8parser;
+pub mod pphd8writer;
+pub mod read_at;
+pub mod resample;
+pub mod wav;
@@ -1,40 +1,176 @@
+use std::borrow::Cow;
 use std::fmt::Display;
 use std::fs::{self, File};
-use std::io::Write;
+use std::io::{BufWriter, Read, Seek, Write};
 use std::mem::size_of;
-use std::os::windows::fs::FileExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
 
+use memmap2::Mmap;
+
+use crate::adpcm::{self, DecodedAudio, LoopRegion};
+use crate::aiff;
+use crate::flac;
+use crate::read_at::{ReadAt, SeekReader};
+use crate::resample;
+use crate::wav;
+
 /// All the data that we know how to extract from a PPHD8 file.
 ///
 /// PPHD8 files have the following structure:
 /// - A **metadata section**, where it specifies other sections in the same file
 /// - An **index section**, where it lists all files contained by the PPHD8
 /// - The actual **raw data** for several VAG files
-pub struct PPHD8FileData {
-    start_of_index: u32, // Start of the index section, extracted from the word at 0x38
+///
+/// The data can be read from any [`ReadAt`] source: a file on disk (the default), a byte slice,
+/// a `Vec<u8>` or any `Read + Seek` type wrapped in a [`SeekReader`].
+pub struct PPHD8FileData<S: ReadAt = File> {
+    header: PPHD8Header,            // Metadata section
+    start_of_index: u64,            // Start of the index section, extracted from the word at 0x38
     vag_entries: Vec<VAGFileEntry>, // All entries inside the file
-    n_files: usize,      // Not extracted but can be computed from the index
-    start_of_data: u32,  // Start of the data section, extracted from the word at 0xC
-    file: File,          // The file we are reading
+    n_files: usize,                 // Not extracted but can be computed from the index
+    start_of_data: u64,             // Start of the data section, extracted from the word at 0xC
+    file_size: u64, // Size of the whole PPHD8 file, every read is checked against it
+    source: S,      // Where we are reading the PPHD8 data from
+    limits: ParseLimits, // Limits used when parsing this file and reading its entries
+}
+
+/// Resource limits enforced while parsing a PPHD8 file and reading its entries.
+///
+/// Sizes and counts come straight from the file, so a corrupt or hostile file could ask
+/// for huge allocations. Going over any of these limits is reported as a [`ParseError`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLimits {
+    /// Max size in bytes of a single VAG body
+    pub max_entry_size: u32,
+    /// Max number of (non null) entries in the index
+    pub max_entry_count: usize,
+    /// Max number of bytes allocated for VAG bodies in a single call to
+    /// [`PPHD8FileData::get_vag_files`]
+    pub max_total_allocation: u64,
+}
+
+/// Signature at the very start of every PPHD8 file
+pub const PPHD8_MAGIC: [u8; 4] = *b"PPHD";
+
+/// Kind of PPHD8 file, as reported by [`detect`]. It depends on the version word at 0x4,
+/// right after the signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PPHD8Variant {
+    /// Version 8 files, the ones that give name to the format and the only ones we know how to parse
+    V8,
+    /// The signature is right but we have never seen this version
+    Unknown(u32),
+}
+
+/// Signature at the start of standalone VAG files
+pub const VAGP_MAGIC: [u8; 4] = *b"VAGp";
+/// Signature at the start of standalone VAG files with interleaved channels
+pub const VAGI_MAGIC: [u8; 4] = *b"VAGi";
+/// Interleave size used when combining channels, a common choice for PS2 streams
+pub const DEFAULT_VAGI_INTERLEAVE: u32 = 0x800;
+
+/// Size of the header of standalone VAG files, the body starts right after it
+const VAGP_HEADER_SIZE: usize = 0x30;
+/// Size of the header written by older versions of this program, with a 32 bytes name
+const LEGACY_VAGP_HEADER_SIZE: usize = 0x40;
+/// Size of the name field of the VAGp header
+const VAGP_NAME_SIZE: usize = 16;
+/// Interleaved files have the same header, padded with zeros up to this size
+const VAGI_HEADER_SIZE: usize = 0x800;
+
+/// Version word of a VAGp header, at 0x4.
+///
+/// Sony's tools changed it over time, and players only use it to tell some variants apart,
+/// so any of the known ones works for plain mono files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VAGVersion {
+    /// 0x2, from the oldest tools
+    V2,
+    /// 0x3, the one older versions of this program always wrote
+    V3,
+    /// 0x4
+    V4,
+    /// 0x6
+    V6,
+    /// 0x20, the most common one, used by the PS2 SDK
+    #[default]
+    V20,
+    /// A version we have never seen, kept as it is
+    Other(u32),
+}
+
+/// Highest alignment guessed for the bodies of a file, the size of a CD sector
+const MAX_BODY_ALIGNMENT: u64 = 0x800;
+/// Bytes copied at a time when writing a modified copy of a PPHD8 file
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of words in the metadata section at the start of the file
+const HEADER_WORDS: usize = 16;
+/// Number of words at the start of the index section, before the first entry
+const INDEX_HEADER_WORDS: usize = 16;
+/// Words of the metadata section we know the meaning of
+const KNOWN_HEADER_WORDS: [usize; 4] = [
+    0,  // 0x00, magic
+    1,  // 0x04, version
+    3,  // 0x0C, start of data section
+    14, // 0x38, start of index section
+];
+
+/// Metadata section of a PPHD8 file: the first 16 words of the file, plus the 16 words at
+/// the start of the index section, right before the first entry.
+///
+/// Only some fields are understood so far, the rest of the words are kept raw so they can be
+/// inspected (and written back untouched). All words are little endian in the file.
+///
+/// | Offset | Field                                         |
+/// |--------|-----------------------------------------------|
+/// | 0x00   | Signature, `PPHD`                             |
+/// | 0x04   | Version                                       |
+/// | 0x08   | Unknown                                       |
+/// | 0x0C   | Start of the data section                     |
+/// | 0x10   | Unknown (10 words, up to 0x34)                |
+/// | 0x38   | Start of the index section                    |
+/// | 0x3C   | Unknown                                       |
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PPHD8Header {
+    words: [u32; HEADER_WORDS],
+    index_header: [u32; INDEX_HEADER_WORDS],
 }
 
 /// A VAG file entry in a PPHD8 file, as it comes from the index section.
-struct VAGFileEntry {
+///
+/// Entries only describe where a VAG body is, so listing them doesn't require reading
+/// any audio data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VAGFileEntry {
     frequency: u32,
     size: u32,
     //how far from the start of the data section in the pphd8 file is this file
-    offset_from_data_start: usize,
+    offset_from_data_start: u32,
+    // Absolute offset of the body in the pphd8 file
+    absolute_offset: u64,
+    // Position of this entry in the index, counting null entries
+    slot_index: usize,
+    // Last word of the entry in the index, after the size. We don't know what it means.
+    padding: u32,
 }
 
-/// A VAG file extracted from the PPHD8 file
-pub struct VAGFile {
+/// A VAG file extracted from the PPHD8 file, or read from a standalone VAG file.
+///
+/// The body is either owned, when it was copied out of the PPHD8 file, or borrowed
+/// directly from the bytes of a PPHD8 file loaded in memory or memory-mapped.
+pub struct VAGFile<'a> {
     frequency: u32,
     size: u32,
+    version: VAGVersion,
     channels: u32,
-    filename: [u8; 32],
-    body: Vec<u8>,
+    // Bytes of each channel before the next one starts, only used with more than one channel
+    interleave: u32,
+    // Name field of the VAGp header, padded with zeros
+    filename: [u8; VAGP_NAME_SIZE],
+    body: Cow<'a, [u8]>,
 }
 
 /// Possible errors that could happen when parsing a VAG file
@@ -46,43 +182,249 @@ pub enum ParseError {
         expected_size: u32,
         actual_size: u32,
     },
+    /// The file ends before a word of the metadata section at `offset`
+    TruncatedHeader {
+        offset: u64,
+    },
+    /// The index entry starting at `offset` goes past the end of the file
+    IndexOutOfBounds {
+        offset: u64,
+    },
+    /// The body of an entry, starting at the absolute `offset`, goes past the end of the file
+    EntryOutsideDataSection {
+        entry_index: usize,
+        offset: u64,
+        size: u32,
+    },
+    /// The index has more entries than [`ParseLimits::max_entry_count`]
+    TooManyEntries {
+        max_entry_count: usize,
+    },
+    /// An entry is bigger than [`ParseLimits::max_entry_size`]
+    EntryTooLarge {
+        entry_index: usize,
+        size: u32,
+        max_entry_size: u32,
+    },
+    /// Reading the entries would allocate more than [`ParseLimits::max_total_allocation`]
+    AllocationLimitExceeded {
+        entry_index: usize,
+        total_allocation: u64,
+        max_total_allocation: u64,
+    },
+    /// There is no entry at `entry_index`, the file only has `n_files` entries
+    EntryNotFound {
+        entry_index: usize,
+        n_files: usize,
+    },
+    /// The file does not start with the PPHD8 signature
+    NotPPHD8,
+    /// The file is a PPHD8 file, but not from a version we know how to parse
+    UnsupportedVersion {
+        version: u32,
+    },
+    /// The file does not start with the VAGp signature
+    NotVAG,
+    /// A standalone VAG file is shorter than its header says
+    IncompleteVagFile {
+        expected_size: u64,
+        actual_size: u64,
+    },
+    /// Interleaved VAG files need between 2 and 255 channels, each coming from a mono file
+    InvalidChannelCount {
+        channel_count: usize,
+    },
+    /// Channels interleaved together must have the same sample rate
+    MismatchedChannelFrequency {
+        channel: usize,
+        frequency: u32,
+        expected_frequency: u32,
+    },
+    /// The interleave size of a VAG file is 0, or not a whole number of ADPCM blocks
+    InvalidInterleave {
+        interleave: u32,
+    },
+    /// The file is not a RIFF/WAVE file, or it has no format or data chunk
+    NotWAV,
+    /// The samples of a WAV file are in a format we can't read
+    UnsupportedWavFormat {
+        format_tag: u16,
+        bits_per_sample: u16,
+    },
+    /// PPHD8 entries are mono, a VAG file with more channels can't be stored as one
+    NotMono {
+        channels: u32,
+    },
+    /// The body of an entry shares bytes with the body of another one, so it can't be
+    /// replaced on its own
+    SharedEntryBody {
+        entry_index: usize,
+        other_entry_index: usize,
+    },
+    /// Audio can't be resampled to 0 Hz or over [`adpcm::MAX_SAMPLE_RATE`]
+    InvalidSampleRate {
+        sample_rate: u32,
+    },
+    /// A new order for the entries of a file doesn't list each of them exactly once
+    InvalidEntryOrder {
+        entry_count: usize,
+    },
 }
 
+/// Read a little endian number from a file, returning `$error` if the file ends before it
 macro_rules! read_from_file {
-    ($file_variable:ident, $type_name:ident, $offset:expr) => {
-        unsafe {
-            let mut buffer = [0u8; std::mem::size_of::<$type_name>()];
-            let result = $file_variable.seek_read(&mut buffer, $offset)?;
-            assert_eq!(result, std::mem::size_of::<$type_name>());
-
-            *(buffer.as_mut_ptr().cast::<u32>())
+    ($file_variable:ident, $type_name:ident, $offset:expr, $error:expr) => {{
+        let mut buffer = [0u8; std::mem::size_of::<$type_name>()];
+        let result = $file_variable.read_at(&mut buffer, $offset)?;
+        if result != std::mem::size_of::<$type_name>() {
+            return Err($error);
         }
-    };
+
+        // PPHD8 files come from the PS2, so words are always little endian
+        $type_name::from_le_bytes(buffer)
+    }};
 }
 
 impl PPHD8FileData {
-    /// Parse a VAG file from a file.
+    /// Parse a PPHD8 file from a file in disk.
     pub fn parse_from_file(filename: &Path) -> Result<PPHD8FileData, ParseError> {
+        Self::parse_from_file_with_limits(filename, ParseLimits::default())
+    }
+
+    /// Same as [`PPHD8FileData::parse_from_file`], using custom limits.
+    pub fn parse_from_file_with_limits(
+        filename: &Path,
+        limits: ParseLimits,
+    ) -> Result<PPHD8FileData, ParseError> {
         // Try to open file:
         let file = fs::File::open(filename)?;
-        let start_of_index = read_from_file!(file, u32, 0x38) + 16 * 4;
-        let start_of_data = read_from_file!(file, u32, 0xC);
+        Self::parse_from_source_with_limits(file, limits)
+    }
+}
+
+impl PPHD8FileData<Mmap> {
+    /// Parse a PPHD8 file from disk by memory-mapping it instead of reading it.
+    ///
+    /// Combined with [`PPHD8FileData::get_vag_files_borrowed`], VAG bodies are never copied.
+    /// The file should not be modified by other processes while it is mapped.
+    pub fn parse_from_file_mmap(filename: &Path) -> Result<Self, ParseError> {
+        Self::parse_from_file_mmap_with_limits(filename, ParseLimits::default())
+    }
+
+    /// Same as [`PPHD8FileData::parse_from_file_mmap`], using custom limits.
+    pub fn parse_from_file_mmap_with_limits(
+        filename: &Path,
+        limits: ParseLimits,
+    ) -> Result<Self, ParseError> {
+        let file = fs::File::open(filename)?;
+        // SAFETY: The map is only ever read, and we document that the file should not be
+        // modified while it is mapped, as any other program reading a file would expect.
+        #[allow(unsafe_code)]
+        let mmap = unsafe { Mmap::map(&file)? };
+        Self::parse_from_source_with_limits(mmap, limits)
+    }
+}
+
+impl<'a> PPHD8FileData<&'a [u8]> {
+    /// Parse a PPHD8 file that is already loaded in memory, borrowing its bytes.
+    pub fn parse_from_bytes(bytes: &'a [u8]) -> Result<Self, ParseError> {
+        Self::parse_from_source(bytes)
+    }
+
+    /// Same as [`PPHD8FileData::parse_from_bytes`], using custom limits.
+    pub fn parse_from_bytes_with_limits(
+        bytes: &'a [u8],
+        limits: ParseLimits,
+    ) -> Result<Self, ParseError> {
+        Self::parse_from_source_with_limits(bytes, limits)
+    }
+}
+
+impl PPHD8FileData<Vec<u8>> {
+    /// Parse a PPHD8 file that is already loaded in memory, taking ownership of its bytes.
+    pub fn parse_from_vec(bytes: Vec<u8>) -> Result<Self, ParseError> {
+        Self::parse_from_source(bytes)
+    }
+
+    /// Same as [`PPHD8FileData::parse_from_vec`], using custom limits.
+    pub fn parse_from_vec_with_limits(
+        bytes: Vec<u8>,
+        limits: ParseLimits,
+    ) -> Result<Self, ParseError> {
+        Self::parse_from_source_with_limits(bytes, limits)
+    }
+}
+
+impl<R: Read + Seek> PPHD8FileData<SeekReader<R>> {
+    /// Parse a PPHD8 file from any readable and seekable source, like a cursor or an
+    /// entry inside a bigger archive.
+    pub fn parse_from_reader(reader: R) -> Result<Self, ParseError> {
+        Self::parse_from_source(SeekReader::new(reader))
+    }
+
+    /// Same as [`PPHD8FileData::parse_from_reader`], using custom limits.
+    pub fn parse_from_reader_with_limits(
+        reader: R,
+        limits: ParseLimits,
+    ) -> Result<Self, ParseError> {
+        Self::parse_from_source_with_limits(SeekReader::new(reader), limits)
+    }
+}
+
+impl<S: ReadAt> PPHD8FileData<S> {
+    /// Parse a PPHD8 file from any source implementing [`ReadAt`].
+    pub fn parse_from_source(file: S) -> Result<Self, ParseError> {
+        Self::parse_from_source_with_limits(file, ParseLimits::default())
+    }
+
+    /// Parse a PPHD8 file from any source implementing [`ReadAt`], using custom limits
+    /// instead of the default ones. The limits are kept to read the entries later.
+    pub fn parse_from_source_with_limits(file: S, limits: ParseLimits) -> Result<Self, ParseError> {
+        // Make sure this is a file we can understand before reading any pointer from it
+        match detect(&file)? {
+            PPHD8Variant::V8 => {}
+            PPHD8Variant::Unknown(version) => {
+                return Err(ParseError::UnsupportedVersion { version })
+            }
+        }
+
+        let file_size = file.len()?;
+        let header = PPHD8Header::parse(&file)?;
+        let start_of_index = header.index_entries_offset();
+        let start_of_data = header.data_offset() as u64;
 
         // Try to parse vag file entries
         let mut vag_entries = vec![];
         let mut n_files = 0;
         let mut index_iterator = start_of_index;
+        let mut slot_index = 0;
+        let word_size = size_of::<u32>() as u64;
+
+        while index_iterator + word_size * 3 < start_of_data {
+            // Each entry is 3 words + padding, check that the words we read are inside the file
+            let out_of_bounds = ParseError::IndexOutOfBounds {
+                offset: index_iterator,
+            };
+            if index_iterator + word_size * 3 > file_size {
+                return Err(out_of_bounds);
+            }
+
+            let offset_from_data_start =
+                read_from_file!(file, u32, index_iterator, out_of_bounds.clone());
+            index_iterator += word_size;
+            let frequency = read_from_file!(file, u32, index_iterator, out_of_bounds.clone());
+            index_iterator += word_size;
+            let size = read_from_file!(file, u32, index_iterator, out_of_bounds);
+            index_iterator += word_size;
 
-        while index_iterator + ((size_of::<u32>() * 3) as u32) < start_of_data {
-            let offset_from_data_start = read_from_file!(file, u32, index_iterator as u64) as usize;
-            index_iterator += size_of::<u32>() as u32;
-            let frequency = read_from_file!(file, u32, index_iterator as u64);
-            index_iterator += size_of::<u32>() as u32;
-            let size = read_from_file!(file, u32, index_iterator as u64);
-            index_iterator += size_of::<u32>() as u32;
+            // padding, left as 0 if the file ends right before it
+            let mut padding = [0];
+            read_words(&file, index_iterator, &mut padding)?;
+            let [padding] = padding;
+            index_iterator += word_size;
 
-            // padding
-            index_iterator += size_of::<u32>() as u32;
+            let entry_slot = slot_index;
+            slot_index += 1;
 
             // If all three values are 0xffffffff, it means this is a null entry, we don't count it
             if offset_from_data_start == 0xFFFFFFFF && frequency == 0xFFFFFFFF && size == 0xFFFFFFFF
@@ -90,133 +432,1863 @@ impl PPHD8FileData {
                 continue;
             }
 
+            if n_files >= limits.max_entry_count {
+                return Err(ParseError::TooManyEntries {
+                    max_entry_count: limits.max_entry_count,
+                });
+            }
+
             vag_entries.push(VAGFileEntry {
                 frequency,
                 size,
                 offset_from_data_start,
+                absolute_offset: start_of_data + offset_from_data_start as u64,
+                slot_index: entry_slot,
+                padding,
             });
             n_files += 1;
         }
 
         Ok(PPHD8FileData {
+            header,
             start_of_index,
             start_of_data,
             vag_entries,
             n_files,
-            file,
+            file_size,
+            source: file,
+            limits,
         })
     }
 
+    /// Metadata section of this file
+    pub fn header(&self) -> &PPHD8Header {
+        &self.header
+    }
+
+    /// All (non null) entries in the index, in the same order as [`PPHD8FileData::get_vag_files`]
+    pub fn entries(&self) -> &[VAGFileEntry] {
+        &self.vag_entries
+    }
+
+    /// Entry at the given position, not counting null entries
+    pub fn entry(&self, index: usize) -> Option<&VAGFileEntry> {
+        self.vag_entries.get(index)
+    }
+
+    /// Number of (non null) entries in this file
+    pub fn n_files(&self) -> usize {
+        self.n_files
+    }
+
+    /// Limits used by this file
+    pub fn limits(&self) -> ParseLimits {
+        self.limits
+    }
+
+    /// Size in bytes of the whole file
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Where the data of this file is read from
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    /// Alignment of the bodies in the data section: the biggest power of two that all their
+    /// offsets are multiple of, up to 0x800. Whole ADPCM blocks if no offset tells.
+    pub fn body_alignment(&self) -> u64 {
+        self.vag_entries
+            .iter()
+            .map(|entry| entry.offset_from_data_start as u64)
+            .filter(|offset| *offset != 0)
+            .map(|offset| 1 << offset.trailing_zeros())
+            .min()
+            .unwrap_or(adpcm::BLOCK_SIZE as u64)
+            .min(MAX_BODY_ALIGNMENT)
+    }
+
     /// Get all VAG files inside this PPHD8File
-    pub fn get_vag_files(&self) -> Result<Vec<VAGFile>, ParseError> {
+    pub fn get_vag_files(&self) -> Result<Vec<VAGFile<'static>>, ParseError> {
         let mut results = vec![];
+        let mut total_allocation: u64 = 0;
 
         for (i, vag_entry) in self.vag_entries.iter().enumerate() {
-            let mut buff = vec![0u8; vag_entry.size as usize];
-            let offset = vag_entry.offset_from_data_start + (self.start_of_data as usize);
-
-            // Read vag file from open file.
-            let result = self.file.seek_read(&mut buff, offset as u64)?;
-            if result != vag_entry.size as usize {
-                return Err(ParseError::IncompleteVag {
-                    entry_index: i,
-                    expected_size: vag_entry.size,
-                    actual_size: result as u32,
-                });
+            total_allocation = total_allocation.saturating_add(vag_entry.size as u64);
+            self.check_allocation(i, total_allocation)?;
+
+            results.push(self.read_vag_file(i, vag_entry)?);
+        }
+
+        Ok(results)
+    }
+
+    /// Read a single VAG file, by its position in [`PPHD8FileData::entries`]
+    pub fn read_entry(&self, index: usize) -> Result<VAGFile<'static>, ParseError> {
+        let vag_entry = self
+            .vag_entries
+            .get(index)
+            .ok_or(ParseError::EntryNotFound {
+                entry_index: index,
+                n_files: self.n_files,
+            })?;
+        self.check_allocation(index, vag_entry.size as u64)?;
+
+        self.read_vag_file(index, vag_entry)
+    }
+
+    /// Iterate over all VAG files inside this PPHD8File, reading them one at a time.
+    ///
+    /// Unlike [`PPHD8FileData::get_vag_files`], only one body is kept in memory at a time
+    /// and an error in one entry does not stop the iteration.
+    pub fn iter_vag_files(&self) -> VAGFileIter<'_, S> {
+        VAGFileIter {
+            pphd8_file: self,
+            next_entry: 0,
+        }
+    }
+
+    /// Write a copy of this file with entry `index` replaced by `vag`, a mono VAG file.
+    ///
+    /// The size and frequency of the entry in the index are updated, every other byte is
+    /// copied as it is. If the new body fits in the space of the old one, the rest of that
+    /// space is filled with zeros. If it doesn't, the bytes after the old body are moved
+    /// forward by a multiple of [`PPHD8FileData::body_alignment`], and the offsets of the
+    /// entries stored after it are updated to match.
+    ///
+    /// Returns [`ParseError::SharedEntryBody`] if other entries use some bytes of its body.
+    pub fn replace_entry<W: Write>(
+        &self,
+        index: usize,
+        vag: &VAGFile,
+        writer: &mut W,
+    ) -> Result<(), ParseError> {
+        if vag.channels() != 1 {
+            return Err(ParseError::NotMono {
+                channels: vag.channels(),
+            });
+        }
+
+        let entry = self
+            .vag_entries
+            .get(index)
+            .ok_or(ParseError::EntryNotFound {
+                entry_index: index,
+                n_files: self.n_files,
+            })?;
+        let body_start = self.entry_start(index, entry)?;
+        let body_end = body_start + entry.size as u64;
+        if let Some(other_entry_index) =
+            self.vag_entries.iter().enumerate().position(|(i, other)| {
+                let other_end = other.absolute_offset + other.size as u64;
+                i != index && other.absolute_offset < body_end && body_start < other_end
+            })
+        {
+            return Err(ParseError::SharedEntryBody {
+                entry_index: index,
+                other_entry_index,
+            });
+        }
+
+        let new_body = vag.body();
+        let new_size = u32::try_from(new_body.len())
+            .ok()
+            .filter(|size| *size <= self.limits.max_entry_size)
+            .ok_or(ParseError::EntryTooLarge {
+                entry_index: index,
+                size: new_body.len().try_into().unwrap_or(u32::MAX),
+                max_entry_size: self.limits.max_entry_size,
+            })?;
+        // The entries after it keep the alignment of the bank
+        let grow =
+            (new_size.saturating_sub(entry.size) as u64).next_multiple_of(self.body_alignment());
+        let too_big = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Too much data for a PPHD8 file",
+            )
+        };
+
+        // The index is small, so it's patched in memory
+        let mut index_section = self.read_index_section()?;
+        let mut patch_word = |slot_index: usize, word: usize, value: u32| {
+            patch_slot_word(&mut index_section, slot_index, word, value)
+        };
+        patch_word(entry.slot_index, 1, vag.frequency());
+        patch_word(entry.slot_index, 2, new_size);
+        if grow > 0 {
+            for (i, other) in self.vag_entries.iter().enumerate() {
+                if i != index && other.absolute_offset >= body_end {
+                    let offset = u32::try_from(other.offset_from_data_start as u64 + grow)
+                        .map_err(|_| too_big())?;
+                    patch_word(other.slot_index, 0, offset);
+                }
+            }
+        }
+
+        self.copy_range(writer, 0, self.start_of_index)?;
+        writer.write_all(&index_section)?;
+        self.copy_range(writer, self.start_of_data, body_start)?;
+        writer.write_all(new_body)?;
+        let zeros = entry.size as u64 + grow - new_size as u64;
+        std::io::copy(&mut std::io::repeat(0).take(zeros), writer)?;
+        self.copy_range(writer, body_end, self.file_size)?;
+
+        Ok(())
+    }
+
+    /// Same as [`PPHD8FileData::replace_entry`], saving the new PPHD8 file to disk.
+    ///
+    /// `filepath` must not be the file this one was read from. It's left as it was if
+    /// anything fails.
+    pub fn replace_entry_to_file(
+        &self,
+        index: usize,
+        vag: &VAGFile,
+        filepath: &Path,
+    ) -> Result<(), ParseError> {
+        write_file_with(filepath, |writer| self.replace_entry(index, vag, writer))
+    }
+
+    /// Write a copy of this file with the frequencies of some entries changed, given as
+    /// `(index, frequency)` pairs, and the entries in `nulls` turned into null entries so the
+    /// ones after them keep their slot.
+    ///
+    /// Only those words of the index change, every other byte is copied as it is. The bodies
+    /// of null entries stay in the file, and so does the padding word of their slot.
+    pub fn edit_entries<W: Write>(
+        &self,
+        frequencies: &[(usize, u32)],
+        nulls: &[usize],
+        writer: &mut W,
+    ) -> Result<(), ParseError> {
+        let slot_index = |index: usize| {
+            self.vag_entries
+                .get(index)
+                .map(|entry| entry.slot_index)
+                .ok_or(ParseError::EntryNotFound {
+                    entry_index: index,
+                    n_files: self.n_files,
+                })
+        };
+
+        let mut index_section = self.read_index_section()?;
+        for &(index, frequency) in frequencies {
+            patch_slot_word(&mut index_section, slot_index(index)?, 1, frequency);
+        }
+        for &index in nulls {
+            let slot_index = slot_index(index)?;
+            for word in 0..3 {
+                patch_slot_word(&mut index_section, slot_index, word, 0xFFFFFFFF);
+            }
+        }
+
+        self.copy_range(writer, 0, self.start_of_index)?;
+        writer.write_all(&index_section)?;
+        let index_end = self.start_of_index + index_section.len() as u64;
+        self.copy_range(writer, index_end, self.file_size)?;
+
+        Ok(())
+    }
+
+    /// Same as [`PPHD8FileData::edit_entries`], saving the new PPHD8 file to disk.
+    ///
+    /// `filepath` must not be the file this one was read from. It's left as it was if
+    /// anything fails.
+    pub fn edit_entries_to_file(
+        &self,
+        frequencies: &[(usize, u32)],
+        nulls: &[usize],
+        filepath: &Path,
+    ) -> Result<(), ParseError> {
+        write_file_with(filepath, |writer| {
+            self.edit_entries(frequencies, nulls, writer)
+        })
+    }
+
+    /// Bytes of the index from its first slot to the data section, empty if they overlap
+    fn read_index_section(&self) -> Result<Vec<u8>, ParseError> {
+        let size = self.start_of_data.saturating_sub(self.start_of_index);
+        let mut index_section = vec![0u8; size as usize];
+        self.source
+            .read_at(&mut index_section, self.start_of_index)?;
+        Ok(index_section)
+    }
+
+    fn read_vag_file(
+        &self,
+        entry_index: usize,
+        vag_entry: &VAGFileEntry,
+    ) -> Result<VAGFile<'static>, ParseError> {
+        let offset = self.entry_start(entry_index, vag_entry)?;
+        let mut buff = vec![0u8; vag_entry.size as usize];
+
+        // Read vag file from open file.
+        let result = self.source.read_at(&mut buff, offset)?;
+        if result != vag_entry.size as usize {
+            return Err(ParseError::IncompleteVag {
+                entry_index,
+                expected_size: vag_entry.size,
+                actual_size: result as u32,
+            });
+        }
+
+        Ok(VAGFile::from_entry(
+            entry_index,
+            vag_entry,
+            Cow::Owned(buff),
+        ))
+    }
+
+    fn check_allocation(
+        &self,
+        entry_index: usize,
+        total_allocation: u64,
+    ) -> Result<(), ParseError> {
+        if total_allocation > self.limits.max_total_allocation {
+            return Err(ParseError::AllocationLimitExceeded {
+                entry_index,
+                total_allocation,
+                max_total_allocation: self.limits.max_total_allocation,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Copy the bytes of this file between two absolute offsets, a chunk at a time
+    fn copy_range<W: Write>(&self, writer: &mut W, start: u64, end: u64) -> Result<(), ParseError> {
+        let mut buffer = vec![0u8; COPY_CHUNK_SIZE];
+        let mut position = start;
+        while position < end {
+            let len = (end - position).min(COPY_CHUNK_SIZE as u64) as usize;
+            let read = self.source.read_at(&mut buffer[..len], position)?;
+            if read != len {
+                return Err(ParseError::IOError(Arc::new(
+                    std::io::ErrorKind::UnexpectedEof.into(),
+                )));
             }
+            writer.write_all(&buffer[..len])?;
+            position += len as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Absolute offset where the body of an entry starts, checking that the whole body
+    /// is inside the file and that it's not bigger than our limits
+    fn entry_start(&self, entry_index: usize, entry: &VAGFileEntry) -> Result<u64, ParseError> {
+        if entry.size > self.limits.max_entry_size {
+            return Err(ParseError::EntryTooLarge {
+                entry_index,
+                size: entry.size,
+                max_entry_size: self.limits.max_entry_size,
+            });
+        }
 
-            let mut filename = [0u8; 32];
-            filename[0] = b'L';
-            filename[1] = b'D';
-            results.push(VAGFile {
-                frequency: vag_entry.frequency,
-                size: vag_entry.size,
-                channels: 0x00000003,
-                filename,
-                body: buff,
+        let offset = entry.absolute_offset;
+        if offset + entry.size as u64 > self.file_size {
+            return Err(ParseError::EntryOutsideDataSection {
+                entry_index,
+                offset,
+                size: entry.size,
             });
         }
 
+        Ok(offset)
+    }
+}
+
+impl<S: ReadAt + AsRef<[u8]>> PPHD8FileData<S> {
+    /// Get all VAG files inside this PPHD8File, borrowing their bodies from the
+    /// bytes in memory instead of copying them.
+    pub fn get_vag_files_borrowed(&self) -> Result<Vec<VAGFile<'_>>, ParseError> {
+        let bytes = self.source.as_ref();
+        let mut results = vec![];
+
+        for (i, vag_entry) in self.vag_entries.iter().enumerate() {
+            // The file size was taken from these same bytes, so the range is valid
+            let offset = self.entry_start(i, vag_entry)? as usize;
+            let body = &bytes[offset..offset + vag_entry.size as usize];
+            results.push(VAGFile::from_entry(i, vag_entry, Cow::Borrowed(body)));
+        }
+
         Ok(results)
     }
 }
 
-impl VAGFile {
-    /// Writes this VAG file to the specified file
-    pub fn write_to_file(&self, filepath: &Path) -> Result<(), std::io::Error> {
-        let mut new_file = fs::File::create(filepath)?;
-        let file_format_buff = [b'V', b'A', b'G', b'p'];
-        let mut channels_buff = get_buff_for_num(self.channels);
-        channels_buff.reverse();
-
-        let zero_buff = [0u8; 4];
-        let mut len_buff = get_buff_for_num(self.size);
-        len_buff.reverse();
-        let mut freq_buff = get_buff_for_num(self.frequency);
-        freq_buff.reverse();
-
-        // Actually write the file
-        let _written = new_file.write(&file_format_buff)?;
-        let _written = new_file.write(&channels_buff)?;
-        let _written = new_file.write(&zero_buff)?;
-        let _written = new_file.write(&len_buff)?;
-        let _written = new_file.write(&freq_buff)?;
-        let _written = new_file.write(&[0u8; 12])?;
-        let _written = new_file.write(&self.filename)?;
-        let _written = new_file.write(self.body.as_slice())?;
+/// Check the signature and version of a PPHD8 file without parsing the rest of it.
+///
+/// Returns [`ParseError::NotPPHD8`] if the signature is not there.
+pub fn detect<S: ReadAt + ?Sized>(file: &S) -> Result<PPHD8Variant, ParseError> {
+    let mut magic = [0u8; 4];
+    if file.read_at(&mut magic, 0)? != magic.len() || magic != PPHD8_MAGIC {
+        return Err(ParseError::NotPPHD8);
+    }
 
-        Ok(())
+    let version = read_from_file!(file, u32, 0x4, ParseError::NotPPHD8);
+    Ok(PPHD8Variant::from_version(version))
+}
+
+/// Same as [`detect`] but for a file in disk
+pub fn detect_file(filename: &Path) -> Result<PPHD8Variant, ParseError> {
+    let file = fs::File::open(filename)?;
+    detect(&file)
+}
+
+impl PPHD8Variant {
+    /// Variant for a version word, as read from the metadata section
+    pub fn from_version(version: u32) -> Self {
+        match version {
+            8 => PPHD8Variant::V8,
+            version => PPHD8Variant::Unknown(version),
+        }
     }
 }
 
-impl Display for ParseError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            ParseError::IOError(error) => write!(f, "Could not operate file. Error: {}", error)?,
-            ParseError::IncompleteVag {
-                entry_index,
-                expected_size,
-                actual_size } =>
-                    write!(f,
-                        "Unable to read full VAG body from file, incomplete body. VAG entry: {entry_index}. Expected size: {expected_size}, Actual size: {actual_size}")?
+impl PPHD8Header {
+    /// Read the metadata section of a PPHD8 file, and the header of its index section.
+    ///
+    /// This does not check the signature, use [`detect`] for that.
+    pub fn parse<S: ReadAt + ?Sized>(file: &S) -> Result<Self, ParseError> {
+        let mut words = [0u32; HEADER_WORDS];
+        let read = read_words(file, 0, &mut words)?;
+        if read < HEADER_WORDS {
+            return Err(ParseError::TruncatedHeader {
+                offset: (read * size_of::<u32>()) as u64,
+            });
         }
-        Ok(())
+
+        let mut header = PPHD8Header {
+            words,
+            index_header: [0u32; INDEX_HEADER_WORDS],
+        };
+
+        let index_offset = header.index_offset() as u64;
+        read_words(file, index_offset, &mut header.index_header).map_err(|_| {
+            ParseError::IndexOutOfBounds {
+                offset: index_offset,
+            }
+        })?;
+
+        Ok(header)
+    }
+
+    /// Signature at 0x0, always [`PPHD8_MAGIC`] in a valid file
+    pub fn magic(&self) -> [u8; 4] {
+        self.words[0].to_le_bytes()
+    }
+
+    /// Version word at 0x4
+    pub fn version(&self) -> u32 {
+        self.words[1]
+    }
+
+    /// Variant of PPHD8 file according to its version
+    pub fn variant(&self) -> PPHD8Variant {
+        PPHD8Variant::from_version(self.version())
+    }
+
+    /// Absolute offset of the data section, where VAG bodies are stored. Word at 0xC.
+    pub fn data_offset(&self) -> u32 {
+        self.words[3]
+    }
+
+    /// Absolute offset of the index section. Word at 0x38.
+    pub fn index_offset(&self) -> u32 {
+        self.words[14]
+    }
+
+    /// Absolute offset of the first entry in the index, right after the index header
+    pub fn index_entries_offset(&self) -> u64 {
+        self.index_offset() as u64 + (INDEX_HEADER_WORDS * size_of::<u32>()) as u64
+    }
+
+    /// Size in bytes of the index entries, from the first entry to the data section
+    pub fn index_size(&self) -> u64 {
+        (self.data_offset() as u64).saturating_sub(self.index_entries_offset())
+    }
+
+    /// Number of 16 bytes slots in the index, counting null entries
+    pub fn index_slot_count(&self) -> u64 {
+        // Same rule as the parser, the last slot only needs its 3 words before the data section
+        match self.index_size() {
+            0..=12 => 0,
+            size => (size - 13) / 16 + 1,
+        }
+    }
+
+    /// Any word of the metadata section, by its offset divided by 4
+    pub fn word(&self, index: usize) -> Option<u32> {
+        self.words.get(index).copied()
+    }
+
+    /// All the words of the metadata section, as they are in the file
+    pub fn words(&self) -> &[u32; HEADER_WORDS] {
+        &self.words
+    }
+
+    /// Words of the metadata section we don't know the meaning of, with their offset
+    pub fn unknown_words(&self) -> impl Iterator<Item = (u64, u32)> + '_ {
+        self.words
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !KNOWN_HEADER_WORDS.contains(i))
+            .map(|(i, word)| ((i * size_of::<u32>()) as u64, *word))
+    }
+
+    /// The 16 words at the start of the index section, before the first entry. We don't
+    /// know what they mean yet.
+    pub fn index_header(&self) -> &[u32; INDEX_HEADER_WORDS] {
+        &self.index_header
     }
 }
 
-impl Display for PPHD8FileData {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "start_of_index: {}", self.start_of_index)?;
-        writeln!(f, "start_of_data: {}", self.start_of_data)?;
-        writeln!(f, "n_files: {}", self.n_files)?;
-        writeln!(f, "vag_entries:")?;
+impl Default for PPHD8Header {
+    /// Header of an empty version 8 file, with all the unknown words set to 0
+    fn default() -> Self {
+        let mut words = [0u32; HEADER_WORDS];
+        words[0] = u32::from_le_bytes(PPHD8_MAGIC);
+        words[1] = 8;
 
-        for (i, entry) in self.vag_entries.iter().enumerate() {
-            writeln!(f, "\t- Entry: {i}")?;
-            writeln!(f, "\t\t+ frequency: {}", entry.frequency)?;
-            writeln!(f, "\t\t+ size: {}", entry.size)?;
-            writeln!(
-                f,
-                "\t\t+ offset_from_data_start: {}",
-                entry.offset_from_data_start
-            )?;
+        PPHD8Header {
+            words,
+            index_header: [0u32; INDEX_HEADER_WORDS],
         }
+    }
+}
 
-        Ok(())
+/// Fill `words` with little endian words read from `offset`. Returns how many words were
+/// read, which is less than `words.len()` if the file ends before.
+fn read_words<S: ReadAt + ?Sized>(
+    file: &S,
+    offset: u64,
+    words: &mut [u32],
+) -> Result<usize, ParseError> {
+    let mut buffer = vec![0u8; std::mem::size_of_val(words)];
+    let read = file.read_at(&mut buffer, offset)?;
+
+    for (word, bytes) in words.iter_mut().zip(buffer.chunks_exact(size_of::<u32>())) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
     }
+
+    Ok(read / size_of::<u32>())
 }
 
-impl From<std::io::Error> for ParseError {
-    fn from(value: std::io::Error) -> Self {
-        ParseError::IOError(Arc::new(value))
+/// Create `filepath` with what `write` writes to it, or leave it as it was if that fails.
+///
+/// Everything is written to a temporary file next to it first, renamed once complete, so an
+/// error halfway doesn't leave an empty or broken file behind.
+fn write_file_with<F>(filepath: &Path, write: F) -> Result<(), ParseError>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), ParseError>,
+{
+    let mut temp_filepath = filepath.as_os_str().to_owned();
+    temp_filepath.push(".tmp");
+    let temp_filepath = PathBuf::from(temp_filepath);
+
+    let result = File::create(&temp_filepath)
+        .map_err(ParseError::from)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+            Ok(writer.flush()?)
+        });
+    match result {
+        Ok(()) => Ok(fs::rename(&temp_filepath, filepath)?),
+        Err(error) => {
+            let _ = fs::remove_file(&temp_filepath);
+            Err(error)
+        }
+    }
+}
+
+/// Overwrite a word of a slot, in the bytes of an index starting at its first slot
+fn patch_slot_word(index_section: &mut [u8], slot_index: usize, word: usize, value: u32) {
+    let position = slot_index * 4 * size_of::<u32>() + word * size_of::<u32>();
+    index_section[position..position + size_of::<u32>()].copy_from_slice(&value.to_le_bytes());
+}
+
+impl ParseLimits {
+    /// No limits at all, only use this with trusted files
+    pub fn unlimited() -> Self {
+        ParseLimits {
+            max_entry_size: u32::MAX,
+            max_entry_count: usize::MAX,
+            max_total_allocation: u64::MAX,
+        }
     }
 }
 
-/// Get a buffer (byte array) from a number
-fn get_buff_for_num(num: u32) -> [u8; 4] {
-    unsafe {
-        let mut buff = [0u8; std::mem::size_of::<u32>()];
-        buff.as_mut_ptr().cast::<u32>().write(num);
-        buff
+impl Default for ParseLimits {
+    /// Limits big enough for any bank we have seen, way smaller than what a corrupt index could ask for
+    fn default() -> Self {
+        ParseLimits {
+            max_entry_size: 64 * 1024 * 1024,
+            max_entry_count: 65536,
+            max_total_allocation: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// Iterator over the VAG files of a PPHD8 file, see [`PPHD8FileData::iter_vag_files`]
+pub struct VAGFileIter<'a, S: ReadAt> {
+    pphd8_file: &'a PPHD8FileData<S>,
+    next_entry: usize,
+}
+
+impl<'a, S: ReadAt> Iterator for VAGFileIter<'a, S> {
+    type Item = Result<VAGFile<'static>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_entry >= self.pphd8_file.n_files {
+            return None;
+        }
+
+        let result = self.pphd8_file.read_entry(self.next_entry);
+        self.next_entry += 1;
+        Some(result)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.pphd8_file.n_files - self.next_entry;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a, S: ReadAt> ExactSizeIterator for VAGFileIter<'a, S> {}
+
+impl VAGFileEntry {
+    /// Sample rate of this entry in Hz
+    pub fn frequency(&self) -> u32 {
+        self.frequency
+    }
+
+    /// Size in bytes of the ADPCM body
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Offset of the body relative to the start of the data section, as stored in the index
+    pub fn offset_from_data_start(&self) -> u32 {
+        self.offset_from_data_start
+    }
+
+    /// Offset of the body from the start of the PPHD8 file
+    pub fn absolute_offset(&self) -> u64 {
+        self.absolute_offset
+    }
+
+    /// Position of this entry in the index, counting the null entries before it
+    pub fn slot_index(&self) -> usize {
+        self.slot_index
+    }
+
+    /// Word after the size in the index, kept so it can be written back untouched
+    pub fn padding(&self) -> u32 {
+        self.padding
+    }
+
+    /// Number of PCM samples in the body. Every 16 bytes ADPCM block holds 28 samples.
+    pub fn sample_count(&self) -> u64 {
+        (self.size as usize / adpcm::BLOCK_SIZE * adpcm::SAMPLES_PER_BLOCK) as u64
+    }
+
+    /// Length of the audio in seconds, 0 if the entry has no frequency
+    pub fn duration_secs(&self) -> f64 {
+        if self.frequency == 0 {
+            return 0.0;
+        }
+
+        self.sample_count() as f64 / self.frequency as f64
+    }
+}
+
+impl<'a> VAGFile<'a> {
+    /// Entries have no names in PPHD8 files, so they are named after their index
+    fn from_entry(entry_index: usize, entry: &VAGFileEntry, body: Cow<'a, [u8]>) -> Self {
+        let vag = VAGFile {
+            frequency: entry.frequency,
+            size: entry.size,
+            version: VAGVersion::default(),
+            channels: 1,
+            interleave: 0,
+            filename: [0u8; VAGP_NAME_SIZE],
+            body,
+        };
+        vag.with_name(&format!("entry_{entry_index}"))
+    }
+
+    /// Parse a standalone VAG file, borrowing its body from `bytes`.
+    ///
+    /// See [`VAGFile::write_vag`] for the layout. Files saved by older versions of this
+    /// program have a 32 bytes name and the body at 0x40, they are detected by their size.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ParseError> {
+        let is_interleaved = match bytes.get(..VAGP_MAGIC.len()) {
+            Some(magic) if magic == VAGP_MAGIC => false,
+            Some(magic) if magic == VAGI_MAGIC => true,
+            _ => return Err(ParseError::NotVAG),
+        };
+        if bytes.len() < VAGP_HEADER_SIZE {
+            return Err(ParseError::IncompleteVagFile {
+                expected_size: VAGP_HEADER_SIZE as u64,
+                actual_size: bytes.len() as u64,
+            });
+        }
+
+        let read_u32 = |offset: usize| {
+            u32::from_be_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+        let version = VAGVersion::from_word(read_u32(0x4));
+        let frequency = read_u32(0x10);
+
+        let (channels, interleave, header_size, size) = if is_interleaved {
+            let channels = (bytes[0x1E] as u32).max(2);
+            let interleave = read_u32(0x8);
+            if interleave == 0 {
+                return Err(ParseError::InvalidInterleave { interleave });
+            }
+            let size = read_u32(0xC) as u64 * channels as u64;
+            (channels, interleave, VAGI_HEADER_SIZE, size)
+        } else {
+            let size = read_u32(0xC) as u64;
+            let header_size = if bytes.len() as u64 == LEGACY_VAGP_HEADER_SIZE as u64 + size {
+                LEGACY_VAGP_HEADER_SIZE
+            } else {
+                VAGP_HEADER_SIZE
+            };
+            (1, 0, header_size, size)
+        };
+
+        let body = usize::try_from(header_size as u64 + size)
+            .ok()
+            .and_then(|body_end| bytes.get(header_size..body_end))
+            .ok_or(ParseError::IncompleteVagFile {
+                expected_size: header_size as u64 + size,
+                actual_size: bytes.len() as u64,
+            })?;
+
+        let mut filename = [0u8; VAGP_NAME_SIZE];
+        filename.copy_from_slice(&bytes[0x20..0x20 + VAGP_NAME_SIZE]);
+
+        Ok(VAGFile {
+            frequency,
+            size: body.len() as u32,
+            version,
+            channels,
+            interleave,
+            filename,
+            body: Cow::Borrowed(body),
+        })
+    }
+
+    /// Encode PCM audio to a new VAG file, with ADPCM loop flags for its loop region.
+    ///
+    /// Each channel is encoded on its own with [`adpcm::encode`], and interleaved with
+    /// [`DEFAULT_VAGI_INTERLEAVE`] if there is more than one.
+    pub fn from_audio(audio: &DecodedAudio) -> Result<VAGFile<'static>, ParseError> {
+        let channels = audio.channels.max(1) as usize;
+        let encoded: Vec<VAGFile<'static>> = (0..channels)
+            .map(|channel| {
+                let samples: Vec<i16> = audio
+                    .samples
+                    .iter()
+                    .skip(channel)
+                    .step_by(channels)
+                    .copied()
+                    .collect();
+                let body = adpcm::encode(&samples, audio.loop_region);
+                VAGFile {
+                    frequency: audio.sample_rate,
+                    size: body.len() as u32,
+                    version: VAGVersion::default(),
+                    channels: 1,
+                    interleave: 0,
+                    filename: [0u8; VAGP_NAME_SIZE],
+                    body: Cow::Owned(body),
+                }
+            })
+            .collect();
+
+        match <[VAGFile; 1]>::try_from(encoded) {
+            Ok([vag]) => Ok(vag),
+            Err(encoded) => VAGFile::interleave_channels(&encoded, DEFAULT_VAGI_INTERLEAVE),
+        }
+    }
+
+    /// Encode a WAV file to a new VAG file, converting it to `sample_rate` if given.
+    ///
+    /// See [`wav::read_wav`] for the supported formats. The loop of its `smpl` chunk, if any,
+    /// is kept as ADPCM loop flags, and stereo files become interleaved VAG files.
+    ///
+    /// Returns [`ParseError::InvalidSampleRate`] if `sample_rate` is 0 or over
+    /// [`adpcm::MAX_SAMPLE_RATE`].
+    pub fn from_wav(
+        bytes: &[u8],
+        sample_rate: Option<u32>,
+    ) -> Result<VAGFile<'static>, ParseError> {
+        if let Some(sample_rate) = sample_rate {
+            if !(1..=adpcm::MAX_SAMPLE_RATE).contains(&sample_rate) {
+                return Err(ParseError::InvalidSampleRate { sample_rate });
+            }
+        }
+
+        let audio = wav::read_wav(bytes)?;
+        let audio = match sample_rate {
+            Some(sample_rate) => resample::resample(&audio, sample_rate),
+            None => audio,
+        };
+        VAGFile::from_audio(&audio)
+    }
+
+    /// Same as [`VAGFile::from_wav`] but for a file in disk
+    pub fn from_wav_file(
+        filename: &Path,
+        sample_rate: Option<u32>,
+    ) -> Result<VAGFile<'static>, ParseError> {
+        VAGFile::from_wav(&fs::read(filename)?, sample_rate)
+    }
+
+    /// Interleave mono VAG files into a single multi-channel file, like the left and right
+    /// channels of a stereo sound that a bank stores as two entries.
+    ///
+    /// The body is made of chunks of `interleave` bytes, one of each channel in turn. Shorter
+    /// channels are padded with silence. The name and version come from the first channel.
+    pub fn interleave_channels(
+        channels: &[VAGFile],
+        interleave: u32,
+    ) -> Result<VAGFile<'static>, ParseError> {
+        let channel_count = channels.iter().map(|vag| vag.channels as usize).sum();
+        if channels.len() != channel_count || !(2..=u8::MAX as usize).contains(&channel_count) {
+            return Err(ParseError::InvalidChannelCount { channel_count });
+        }
+        if interleave == 0 || !(interleave as usize).is_multiple_of(adpcm::BLOCK_SIZE) {
+            return Err(ParseError::InvalidInterleave { interleave });
+        }
+        let first = &channels[0];
+        for (channel, vag) in channels.iter().enumerate() {
+            if vag.frequency != first.frequency {
+                return Err(ParseError::MismatchedChannelFrequency {
+                    channel,
+                    frequency: vag.frequency,
+                    expected_frequency: first.frequency,
+                });
+            }
+        }
+
+        let interleave_size = interleave as usize;
+        let longest = channels.iter().map(|vag| vag.body.len()).max().unwrap_or(0);
+        let n_chunks = longest.div_ceil(interleave_size);
+        let mut body = Vec::with_capacity(n_chunks * interleave_size * channel_count);
+        for chunk in 0..n_chunks {
+            for vag in channels {
+                let start = (chunk * interleave_size).min(vag.body.len());
+                let end = ((chunk + 1) * interleave_size).min(vag.body.len());
+                body.extend_from_slice(&vag.body[start..end]);
+                body.resize(body.len() + interleave_size - (end - start), 0);
+            }
+        }
+
+        Ok(VAGFile {
+            frequency: first.frequency,
+            size: body.len() as u32,
+            version: first.version,
+            channels: channel_count as u32,
+            interleave,
+            filename: first.filename,
+            body: Cow::Owned(body),
+        })
+    }
+
+    /// Same as [`VAGFile::parse`] but for a file in disk
+    pub fn parse_from_file(filename: &Path) -> Result<VAGFile<'static>, ParseError> {
+        let bytes = fs::read(filename)?;
+        Ok(VAGFile::parse(&bytes)?.into_owned())
+    }
+
+    /// Raw ADPCM body of this VAG file, without any header
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Sample rate of this file in Hz
+    pub fn frequency(&self) -> u32 {
+        self.frequency
+    }
+
+    /// Version of the VAGp header
+    pub fn version(&self) -> VAGVersion {
+        self.version
+    }
+
+    /// Change the version written in the VAGp header
+    pub fn with_version(mut self, version: VAGVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Number of channels of the body
+    pub fn channels(&self) -> u32 {
+        self.channels
+    }
+
+    /// Size in bytes of each chunk of a channel in the body, 0 for mono files
+    pub fn interleave(&self) -> u32 {
+        self.interleave
+    }
+
+    /// ADPCM body of each channel, taken apart from the interleaved body if there is
+    /// more than one
+    pub fn channel_bodies(&self) -> Vec<Cow<'_, [u8]>> {
+        if self.channels <= 1 {
+            return vec![Cow::Borrowed(&self.body)];
+        }
+
+        let mut bodies = vec![vec![]; self.channels as usize];
+        for (i, chunk) in self.body.chunks(self.interleave as usize).enumerate() {
+            bodies[i % self.channels as usize].extend_from_slice(chunk);
+        }
+        bodies.into_iter().map(Cow::Owned).collect()
+    }
+
+    /// Name stored in the VAGp header, up to the first null byte
+    pub fn name(&self) -> String {
+        let end = self
+            .filename
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(self.filename.len());
+        String::from_utf8_lossy(&self.filename[..end]).into_owned()
+    }
+
+    /// Change the name written in the VAGp header. It only has room for 16 bytes, longer
+    /// names are cut.
+    pub fn with_name(mut self, name: &str) -> Self {
+        let name = name.as_bytes();
+        let len = name.len().min(VAGP_NAME_SIZE);
+        self.filename = [0u8; VAGP_NAME_SIZE];
+        self.filename[..len].copy_from_slice(&name[..len]);
+        self
+    }
+
+    /// Decode the PS-ADPCM body to 16 bits PCM, at this file's frequency.
+    ///
+    /// Channels are decoded on their own and interleaved sample by sample. If one ends
+    /// before the others, it's padded with silence.
+    pub fn decode(&self) -> DecodedAudio {
+        let mut channels: Vec<Vec<i16>> = self
+            .channel_bodies()
+            .iter()
+            .map(|body| adpcm::decode(body))
+            .collect();
+
+        let samples = if channels.len() == 1 {
+            channels.pop().unwrap_or_default()
+        } else {
+            let frame_count = channels.iter().map(Vec::len).max().unwrap_or(0);
+            let mut samples = Vec::with_capacity(frame_count * channels.len());
+            for i in 0..frame_count {
+                for channel in &channels {
+                    samples.push(channel.get(i).copied().unwrap_or(0));
+                }
+            }
+            samples
+        };
+
+        DecodedAudio {
+            samples,
+            sample_rate: self.frequency,
+            channels: self.channels as u16,
+            loop_region: self.loop_region(),
+        }
+    }
+
+    /// Loop region of this file according to the ADPCM flags of its first channel, in samples
+    pub fn loop_region(&self) -> Option<LoopRegion> {
+        adpcm::find_loop(&self.channel_bodies()[0])
+    }
+
+    /// Decode this VAG file and write it as a WAV file
+    pub fn write_wav_file(&self, filepath: &Path) -> Result<(), std::io::Error> {
+        wav::write_wav_file(filepath, &self.decode())
+    }
+
+    /// Decode this VAG file and write it as an AIFF file
+    pub fn write_aiff_file(&self, filepath: &Path) -> Result<(), std::io::Error> {
+        aiff::write_aiff_file(filepath, &self.decode())
+    }
+
+    /// Decode this VAG file and write it as a FLAC file, with `comments` as Vorbis comments
+    pub fn write_flac_file(
+        &self,
+        filepath: &Path,
+        comments: &[(&str, String)],
+    ) -> Result<(), std::io::Error> {
+        flac::write_flac_file(filepath, &self.decode(), comments)
+    }
+
+    /// Whether the body of this file is borrowed from a PPHD8 file in memory
+    pub fn is_borrowed(&self) -> bool {
+        matches!(self.body, Cow::Borrowed(_))
+    }
+
+    /// Take the body out of this file, without copying it
+    pub fn into_body(self) -> Cow<'a, [u8]> {
+        self.body
+    }
+
+    /// Copy the body if it is borrowed, so this file no longer depends on the PPHD8 data
+    pub fn into_owned(self) -> VAGFile<'static> {
+        VAGFile {
+            frequency: self.frequency,
+            size: self.size,
+            version: self.version,
+            channels: self.channels,
+            interleave: self.interleave,
+            filename: self.filename,
+            body: Cow::Owned(self.body.into_owned()),
+        }
+    }
+
+    /// Write this VAG file as a standalone VAGp file, or VAGi if it has more than one channel.
+    ///
+    /// The header is 0x30 bytes, all fields in big endian:
+    ///
+    /// | Offset | Size | Field                                          |
+    /// |--------|------|------------------------------------------------|
+    /// | 0x00   | 4    | "VAGp" or "VAGi" signature                     |
+    /// | 0x04   | 4    | version, see [`VAGVersion`]                    |
+    /// | 0x08   | 4    | interleave size in VAGi files, reserved in VAGp |
+    /// | 0x0C   | 4    | size of the body of each channel in bytes      |
+    /// | 0x10   | 4    | sample rate in Hz                              |
+    /// | 0x14   | 10   | reserved                                       |
+    /// | 0x1E   | 1    | number of channels, 0 for mono                 |
+    /// | 0x1F   | 1    | reserved                                       |
+    /// | 0x20   | 16   | name, padded with zeros                        |
+    ///
+    /// The ADPCM body goes right after it in VAGp files. VAGi files pad the header with
+    /// zeros up to 0x800, and the body has the channels interleaved.
+    pub fn write_vag<W: Write>(&self, writer: &mut W) -> Result<(), std::io::Error> {
+        let is_interleaved = self.channels > 1;
+        let (magic, interleave, channels) = if is_interleaved {
+            (VAGI_MAGIC, self.interleave, self.channels as u8)
+        } else {
+            // Most tools write 0 for mono files, and some players get confused by a 1
+            (VAGP_MAGIC, 0, 0)
+        };
+        let channel_size = self.size / self.channels.max(1);
+
+        writer.write_all(&magic)?;
+        writer.write_all(&self.version.word().to_be_bytes())?;
+        writer.write_all(&interleave.to_be_bytes())?;
+        writer.write_all(&channel_size.to_be_bytes())?;
+        writer.write_all(&self.frequency.to_be_bytes())?;
+        writer.write_all(&[0u8; 10])?;
+        writer.write_all(&[channels, 0])?;
+        writer.write_all(&self.filename)?;
+        if is_interleaved {
+            writer.write_all(&[0u8; VAGI_HEADER_SIZE - VAGP_HEADER_SIZE])?;
+        }
+        writer.write_all(&self.body)?;
+
+        Ok(())
+    }
+
+    /// Writes this VAG file to the specified file
+    pub fn write_to_file(&self, filepath: &Path) -> Result<(), std::io::Error> {
+        let mut writer = BufWriter::new(fs::File::create(filepath)?);
+        self.write_vag(&mut writer)?;
+        writer.flush()
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::IOError(error) => write!(f, "Could not operate file. Error: {}", error)?,
+            ParseError::IncompleteVag {
+                entry_index,
+                expected_size,
+                actual_size } =>
+                    write!(f,
+                        "Unable to read full VAG body from file, incomplete body. VAG entry: {entry_index}. Expected size: {expected_size}, Actual size: {actual_size}")?,
+            ParseError::TruncatedHeader { offset } =>
+                write!(f, "File too short to be a PPHD8 file, could not read the header word at {offset:#x}")?,
+            ParseError::IndexOutOfBounds { offset } =>
+                write!(f, "Index entry at {offset:#x} goes past the end of the file")?,
+            ParseError::EntryOutsideDataSection {
+                entry_index,
+                offset,
+                size } =>
+                    write!(f,
+                        "VAG entry {entry_index} is outside the data section. Offset: {offset:#x}, Size: {size}")?,
+            ParseError::TooManyEntries { max_entry_count } =>
+                write!(f, "Too many entries in the index, the limit is {max_entry_count}")?,
+            ParseError::EntryTooLarge {
+                entry_index,
+                size,
+                max_entry_size } =>
+                    write!(f,
+                        "VAG entry {entry_index} is too large. Size: {size}, Limit: {max_entry_size}")?,
+            ParseError::AllocationLimitExceeded {
+                entry_index,
+                total_allocation,
+                max_total_allocation } =>
+                    write!(f,
+                        "Reading VAG entry {entry_index} would allocate too much memory. Total: {total_allocation}, Limit: {max_total_allocation}")?,
+            ParseError::EntryNotFound {
+                entry_index,
+                n_files } =>
+                    write!(f, "There is no VAG entry {entry_index}, the file only has {n_files} entries")?,
+            ParseError::NotPPHD8 => write!(f, "Not a PPHD8 file, the PPHD signature is missing")?,
+            ParseError::UnsupportedVersion { version } =>
+                write!(f, "Unsupported PPHD8 version: {version}")?,
+            ParseError::NotVAG => write!(f, "Not a VAG file, the VAGp or VAGi signature is missing")?,
+            ParseError::IncompleteVagFile {
+                expected_size,
+                actual_size } =>
+                    write!(f,
+                        "Incomplete VAG file. Expected size: {expected_size}, Actual size: {actual_size}")?,
+            ParseError::InvalidChannelCount { channel_count } =>
+                write!(f, "Can't interleave {channel_count} channels, it takes between 2 and 255 mono channels")?,
+            ParseError::MismatchedChannelFrequency {
+                channel,
+                frequency,
+                expected_frequency } =>
+                    write!(f,
+                        "Channel {channel} has a sample rate of {frequency} Hz, but the first one has {expected_frequency} Hz")?,
+            ParseError::InvalidInterleave { interleave } =>
+                write!(f, "Invalid interleave size: {interleave:#x}")?,
+            ParseError::NotWAV => write!(f, "Not a WAV file, or it has no fmt or data chunk")?,
+            ParseError::UnsupportedWavFormat {
+                format_tag,
+                bits_per_sample } =>
+                    write!(f,
+                        "Unsupported WAV format. Format tag: {format_tag:#x}, Bits per sample: {bits_per_sample}")?,
+            ParseError::NotMono { channels } =>
+                write!(f, "PPHD8 entries are mono, but the VAG file has {channels} channels")?,
+            ParseError::SharedEntryBody { entry_index, other_entry_index } =>
+                write!(f, "VAG entry {entry_index} shares its body with entry {other_entry_index}, it can't be replaced on its own")?,
+            ParseError::InvalidSampleRate { sample_rate } =>
+                write!(f, "Invalid sample rate {sample_rate} Hz, it must be between 1 and {} Hz", adpcm::MAX_SAMPLE_RATE)?,
+            ParseError::InvalidEntryOrder { entry_count } =>
+                write!(f, "The new order of the entries must list each of them once, from 0 to {}", entry_count.saturating_sub(1))?,
+        }
+        Ok(())
+    }
+}
+
+impl Display for PPHD8Header {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "magic: {}", String::from_utf8_lossy(&self.magic()))?;
+        writeln!(f, "version: {}", self.version())?;
+        writeln!(f, "data_offset: {:#x}", self.data_offset())?;
+        writeln!(f, "index_offset: {:#x}", self.index_offset())?;
+        writeln!(f, "index_slot_count: {}", self.index_slot_count())?;
+        writeln!(f, "unknown_words:")?;
+        for (offset, word) in self.unknown_words() {
+            writeln!(f, "\t- {offset:#04x}: {word:#010x}")?;
+        }
+        writeln!(f, "index_header:")?;
+        for (i, word) in self.index_header.iter().enumerate() {
+            writeln!(f, "\t- {:#04x}: {word:#010x}", i * size_of::<u32>())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: ReadAt> Display for PPHD8FileData<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "header:")?;
+        for line in self.header.to_string().lines() {
+            writeln!(f, "\t{line}")?;
+        }
+        writeln!(f, "start_of_index: {}", self.start_of_index)?;
+        writeln!(f, "start_of_data: {}", self.start_of_data)?;
+        writeln!(f, "file_size: {}", self.file_size)?;
+        writeln!(f, "n_files: {}", self.n_files)?;
+        writeln!(f, "vag_entries:")?;
+
+        for (i, entry) in self.vag_entries.iter().enumerate() {
+            writeln!(f, "\t- Entry: {i}")?;
+            writeln!(f, "\t\t+ slot_index: {}", entry.slot_index)?;
+            writeln!(f, "\t\t+ frequency: {}", entry.frequency)?;
+            writeln!(f, "\t\t+ size: {}", entry.size)?;
+            writeln!(
+                f,
+                "\t\t+ offset_from_data_start: {}",
+                entry.offset_from_data_start
+            )?;
+            writeln!(f, "\t\t+ absolute_offset: {}", entry.absolute_offset)?;
+            writeln!(f, "\t\t+ sample_count: {}", entry.sample_count())?;
+            writeln!(f, "\t\t+ duration: {:.3}s", entry.duration_secs())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl VAGVersion {
+    /// Version for a version word, as read from a header
+    pub fn from_word(word: u32) -> Self {
+        match word {
+            0x2 => VAGVersion::V2,
+            0x3 => VAGVersion::V3,
+            0x4 => VAGVersion::V4,
+            0x6 => VAGVersion::V6,
+            0x20 => VAGVersion::V20,
+            word => VAGVersion::Other(word),
+        }
+    }
+
+    /// Version word written in the header
+    pub fn word(&self) -> u32 {
+        match self {
+            VAGVersion::V2 => 0x2,
+            VAGVersion::V3 => 0x3,
+            VAGVersion::V4 => 0x4,
+            VAGVersion::V6 => 0x6,
+            VAGVersion::V20 => 0x20,
+            VAGVersion::Other(word) => *word,
+        }
+    }
+}
+
+impl FromStr for VAGVersion {
+    type Err = String;
+
+    /// Parse a version word, in decimal or in hexadecimal with a `0x` prefix
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let word = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => s.parse(),
+        };
+        word.map(VAGVersion::from_word)
+            .map_err(|_| format!("Invalid VAG version '{s}', use a number like 0x20 or 3"))
+    }
+}
+
+impl Display for VAGVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#x}", self.word())
+    }
+}
+
+impl Display for VAGFile<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let channel_size = self.body.len() / self.channels.max(1) as usize;
+        let sample_count = channel_size / adpcm::BLOCK_SIZE * adpcm::SAMPLES_PER_BLOCK;
+        writeln!(f, "name: {}", self.name())?;
+        writeln!(f, "version: {}", self.version)?;
+        writeln!(f, "frequency: {}", self.frequency)?;
+        writeln!(f, "channels: {}", self.channels)?;
+        if self.channels > 1 {
+            writeln!(f, "interleave: {:#x}", self.interleave)?;
+        }
+        writeln!(f, "size: {}", self.size)?;
+        writeln!(f, "sample_count: {sample_count}")?;
+        if self.frequency != 0 {
+            writeln!(
+                f,
+                "duration: {:.3}s",
+                sample_count as f64 / self.frequency as f64
+            )?;
+        }
+        match self.loop_region() {
+            Some(loop_region) => writeln!(f, "loop: {}..{}", loop_region.start, loop_region.end)?,
+            None => writeln!(f, "loop: none")?,
+        }
+
+        Ok(())
+    }
+}
+
+impl From<std::io::Error> for ParseError {
+    fn from(value: std::io::Error) -> Self {
+        ParseError::IOError(Arc::new(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bank with the index right after the metadata section, one slot per
+    /// `[offset, frequency, size]` and the data section right after the index
+    fn bank(slots: &[[u32; 3]], data: &[u8]) -> Vec<u8> {
+        let mut words = [0u32; HEADER_WORDS + INDEX_HEADER_WORDS];
+        words[0] = u32::from_le_bytes(PPHD8_MAGIC);
+        words[1] = 8;
+        words[3] = (0x80 + slots.len() * 16) as u32;
+        words[14] = 0x40;
+
+        let mut bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        for slot in slots {
+            bytes.extend(slot.iter().chain(&[0]).flat_map(|word| word.to_le_bytes()));
+        }
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn truncated_headers_are_reported_with_where_they_end() {
+        let bytes = bank(&[[0, 22050, 0x10]], &[0; 0x10]);
+
+        let result = PPHD8FileData::parse_from_bytes(&bytes[..0x22]);
+        assert!(matches!(
+            result,
+            Err(ParseError::TruncatedHeader { offset: 0x20 })
+        ));
+    }
+
+    #[test]
+    fn index_slots_past_the_end_of_the_file_are_reported() {
+        // The file ends in the middle of the second slot, before the data section
+        let bytes = bank(&[[0, 22050, 0x10], [0x10, 22050, 0x10]], &[0; 0x20]);
+
+        let result = PPHD8FileData::parse_from_bytes(&bytes[..0x98]);
+        assert!(matches!(
+            result,
+            Err(ParseError::IndexOutOfBounds { offset: 0x90 })
+        ));
+    }
+
+    #[test]
+    fn index_sections_past_the_end_of_the_file_are_reported() {
+        let mut bytes = bank(&[], &[]);
+        bytes[0x38..0x3C].copy_from_slice(&0x1000u32.to_le_bytes());
+        bytes[0xC..0x10].copy_from_slice(&0x2000u32.to_le_bytes());
+
+        let result = PPHD8FileData::parse_from_bytes(&bytes);
+        assert!(matches!(
+            result,
+            Err(ParseError::IndexOutOfBounds { offset: 0x1040 })
+        ));
+    }
+
+    #[test]
+    fn bodies_past_the_end_of_the_file_are_reported_when_read() {
+        let bytes = bank(&[[0, 22050, 0x10], [0x10, 22050, 0x20]], &[0; 0x20]);
+        let file = PPHD8FileData::parse_from_bytes(&bytes).unwrap();
+        assert_eq!(file.n_files(), 2);
+
+        let is_outside = |result: Result<_, ParseError>| {
+            matches!(
+                result,
+                Err(ParseError::EntryOutsideDataSection {
+                    entry_index: 1,
+                    offset: 0xB0,
+                    size: 0x20,
+                })
+            )
+        };
+        assert!(is_outside(file.get_vag_files().map(|_| ())));
+        assert!(is_outside(file.get_vag_files_borrowed().map(|_| ())));
+        assert!(is_outside(file.read_entry(1).map(|_| ())));
+        assert_eq!(file.read_entry(0).unwrap().body(), &[0; 0x10]);
+    }
+
+    fn limits(
+        max_entry_size: u32,
+        max_entry_count: usize,
+        max_total_allocation: u64,
+    ) -> ParseLimits {
+        ParseLimits {
+            max_entry_size,
+            max_entry_count,
+            max_total_allocation,
+        }
+    }
+
+    #[test]
+    fn too_many_entries_are_reported_while_parsing() {
+        let bytes = bank(
+            &[[0, 22050, 0x10], [0x10, 22050, 0x10], [0x20, 22050, 0x10]],
+            &[0; 0x30],
+        );
+
+        let result = PPHD8FileData::parse_from_bytes_with_limits(&bytes, limits(0x10, 2, 0x30));
+        assert!(matches!(
+            result,
+            Err(ParseError::TooManyEntries { max_entry_count: 2 })
+        ));
+
+        // Null entries don't count
+        let bytes = bank(
+            &[[0, 22050, 0x10], [0xFFFFFFFF; 3], [0x10, 22050, 0x10]],
+            &[0; 0x20],
+        );
+        let file = PPHD8FileData::parse_from_vec_with_limits(bytes, limits(0x10, 2, 0x20)).unwrap();
+        assert_eq!(file.n_files(), 2);
+        assert_eq!(file.limits(), limits(0x10, 2, 0x20));
+    }
+
+    #[test]
+    fn limits_are_used_when_parsing_files_from_disk() {
+        let bytes = bank(&[[0, 22050, 0x10], [0x10, 22050, 0x10]], &[0; 0x20]);
+        let path = std::env::temp_dir().join(format!("pphd8_limits_{}.pphd8", std::process::id()));
+        fs::write(&path, &bytes).unwrap();
+
+        let from_file = PPHD8FileData::parse_from_file_with_limits(&path, limits(0x10, 1, 0x20));
+        let from_mmap =
+            PPHD8FileData::parse_from_file_mmap_with_limits(&path, limits(0x10, 1, 0x20));
+        let from_reader = PPHD8FileData::parse_from_reader_with_limits(
+            std::io::Cursor::new(&bytes),
+            limits(0x10, 1, 0x20),
+        );
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            from_file,
+            Err(ParseError::TooManyEntries { max_entry_count: 1 })
+        ));
+        assert!(matches!(
+            from_mmap,
+            Err(ParseError::TooManyEntries { max_entry_count: 1 })
+        ));
+        assert!(matches!(
+            from_reader,
+            Err(ParseError::TooManyEntries { max_entry_count: 1 })
+        ));
+    }
+
+    #[test]
+    fn entries_too_large_are_reported_when_read() {
+        let bytes = bank(&[[0, 22050, 0x10], [0x10, 22050, 0x20]], &[0; 0x30]);
+        let file =
+            PPHD8FileData::parse_from_bytes_with_limits(&bytes, limits(0x10, 2, 0x100)).unwrap();
+
+        let is_too_large = |result: Result<_, ParseError>| {
+            matches!(
+                result,
+                Err(ParseError::EntryTooLarge {
+                    entry_index: 1,
+                    size: 0x20,
+                    max_entry_size: 0x10,
+                })
+            )
+        };
+        assert!(is_too_large(file.get_vag_files().map(|_| ())));
+        assert!(is_too_large(file.get_vag_files_borrowed().map(|_| ())));
+        assert!(is_too_large(file.read_entry(1).map(|_| ())));
+        assert!(file.read_entry(0).is_ok());
+    }
+
+    #[test]
+    fn total_allocation_adds_up_every_entry() {
+        // Each entry is under the limit on its own, but not both of them together
+        let bytes = bank(&[[0, 22050, 0x10], [0x10, 22050, 0x10]], &[0; 0x20]);
+        let file =
+            PPHD8FileData::parse_from_bytes_with_limits(&bytes, limits(0x10, 2, 0x18)).unwrap();
+
+        assert!(matches!(
+            file.get_vag_files(),
+            Err(ParseError::AllocationLimitExceeded {
+                entry_index: 1,
+                total_allocation: 0x20,
+                max_total_allocation: 0x18,
+            })
+        ));
+        assert!(file.read_entry(0).is_ok());
+        assert!(file.read_entry(1).is_ok());
+    }
+
+    #[test]
+    fn huge_entries_are_not_allocated_even_without_limits() {
+        // The sizes add up past u32::MAX, and the bodies are not in the file
+        let slots = [[0, 22050, u32::MAX]; 3];
+        let bytes = bank(&slots, &[0; 0x10]);
+        let file =
+            PPHD8FileData::parse_from_bytes_with_limits(&bytes, ParseLimits::unlimited()).unwrap();
+
+        assert!(matches!(
+            file.get_vag_files(),
+            Err(ParseError::EntryOutsideDataSection {
+                entry_index: 0,
+                size: u32::MAX,
+                ..
+            })
+        ));
+
+        let file = PPHD8FileData::parse_from_bytes_with_limits(
+            &bytes,
+            limits(u32::MAX, 3, u64::from(u32::MAX)),
+        )
+        .unwrap();
+        assert!(matches!(
+            file.get_vag_files(),
+            Err(ParseError::EntryOutsideDataSection { entry_index: 0, .. })
+        ));
+        let file = PPHD8FileData::parse_from_bytes_with_limits(
+            &bytes,
+            limits(u32::MAX, 3, u64::from(u32::MAX) - 1),
+        )
+        .unwrap();
+        assert!(matches!(
+            file.get_vag_files(),
+            Err(ParseError::AllocationLimitExceeded { entry_index: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn files_without_the_signature_are_not_pphd8() {
+        let bytes = bank(&[], &[]);
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[..4].copy_from_slice(b"VAGp");
+
+        for bytes in [&[][..], &bytes[..3], &bytes[..6], &wrong_magic] {
+            assert!(matches!(
+                PPHD8FileData::parse_from_bytes(bytes),
+                Err(ParseError::NotPPHD8)
+            ));
+        }
+
+        let mut version_3 = bytes.clone();
+        version_3[4] = 3;
+        assert!(matches!(
+            PPHD8FileData::parse_from_bytes(&version_3),
+            Err(ParseError::UnsupportedVersion { version: 3 })
+        ));
+    }
+
+    #[test]
+    fn cut_or_corrupt_banks_never_panic() {
+        let bytes = bank(
+            &[[0x10, 22050, 0x10], [0xFFFFFFFF; 3], [0, 11025, 0x20]],
+            &[7; 0x20],
+        );
+
+        // The same reads as the fuzz targets, for every length and for every corrupt byte
+        let read_all = |bytes: &[u8]| {
+            if let Ok(file) = PPHD8FileData::parse_from_bytes(bytes) {
+                let _ = file.to_string();
+                let _ = file.get_vag_files();
+                let _ = file.get_vag_files_borrowed();
+            }
+            if let Ok(file) = PPHD8FileData::parse_from_reader(std::io::Cursor::new(bytes)) {
+                let _ = file.get_vag_files();
+            }
+        };
+        for len in 0..=bytes.len() {
+            read_all(&bytes[..len]);
+        }
+        for position in 8..0xC0 {
+            for value in [0x00, 0x7F, 0x80, 0xFF] {
+                let mut corrupt = bytes.clone();
+                corrupt[position] = value;
+                read_all(&corrupt);
+            }
+        }
+    }
+
+    /// A mono VAG file encoding a short ramp, with a name
+    fn mono_vag(frequency: u32, name: &str) -> VAGFile<'static> {
+        let audio = DecodedAudio {
+            samples: (0..100).map(|i| i * 100).collect(),
+            sample_rate: frequency,
+            channels: 1,
+            loop_region: None,
+        };
+        VAGFile::from_audio(&audio).unwrap().with_name(name)
+    }
+
+    /// VAGp header fields in big endian, as written by [`VAGFile::write_vag`]
+    fn vagp_header(version: u32, size: u32, frequency: u32, name: &[u8]) -> Vec<u8> {
+        let mut bytes = b"VAGp".to_vec();
+        for word in [version, 0, size, frequency] {
+            bytes.extend(word.to_be_bytes());
+        }
+        bytes.extend([0; 12]);
+        bytes.extend(name);
+        bytes
+    }
+
+    #[test]
+    fn parse_reads_vagp_headers() {
+        let mut bytes = vagp_header(0x20, 0x20, 22050, b"voice\0\0\0\0\0\0\0\0\0\0\0");
+        bytes.extend(1..=0x20);
+        // Anything after the body is not part of it
+        bytes.extend([0xFF; 0x20]);
+
+        let vag = VAGFile::parse(&bytes).unwrap();
+        assert_eq!(vag.version(), VAGVersion::V20);
+        assert_eq!(vag.frequency(), 22050);
+        assert_eq!(vag.channels(), 1);
+        assert_eq!(vag.interleave(), 0);
+        assert_eq!(vag.name(), "voice");
+        assert_eq!(vag.body(), (1..=0x20).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn parse_reads_the_headers_of_older_versions_of_this_program() {
+        // Channels word at 0x4, which is read as the version, and a 32 bytes name
+        let mut name = [0u8; 32];
+        name[..2].copy_from_slice(b"LD");
+        let mut bytes = vagp_header(3, 0x20, 11025, &name);
+        assert_eq!(bytes.len(), LEGACY_VAGP_HEADER_SIZE);
+        bytes.extend(1..=0x20);
+
+        let vag = VAGFile::parse(&bytes).unwrap();
+        assert_eq!(vag.version(), VAGVersion::V3);
+        assert_eq!(vag.frequency(), 11025);
+        assert_eq!(vag.name(), "LD");
+        assert_eq!(vag.body(), (1..=0x20).collect::<Vec<u8>>());
+
+        // With any other amount of bytes after the body, the header is the usual one
+        bytes.extend([0; 0x20]);
+        let vag = VAGFile::parse(&bytes).unwrap();
+        assert_eq!(
+            vag.body(),
+            &bytes[VAGP_HEADER_SIZE..VAGP_HEADER_SIZE + 0x20]
+        );
+    }
+
+    #[test]
+    fn parse_reads_interleaved_vagi_files() {
+        let channels = [mono_vag(22050, "left"), mono_vag(22050, "right")];
+        let stereo = VAGFile::interleave_channels(&channels, 0x20).unwrap();
+        let mut bytes = vec![];
+        stereo.write_vag(&mut bytes).unwrap();
+
+        assert_eq!(bytes[..4], VAGI_MAGIC);
+        assert_eq!(bytes[0x1E], 2);
+        assert_eq!(bytes.len(), VAGI_HEADER_SIZE + stereo.body().len());
+
+        let vag = VAGFile::parse(&bytes).unwrap();
+        assert_eq!(vag.channels(), 2);
+        assert_eq!(vag.interleave(), 0x20);
+        assert_eq!(vag.frequency(), 22050);
+        assert_eq!(vag.name(), "left");
+        assert_eq!(vag.body(), stereo.body());
+        let bodies = vag.channel_bodies();
+        for (body, channel) in bodies.iter().zip(&channels) {
+            assert_eq!(body[..channel.body().len()], *channel.body());
+        }
+    }
+
+    #[test]
+    fn parse_rejects_truncated_or_unknown_files() {
+        assert!(matches!(VAGFile::parse(&[]), Err(ParseError::NotVAG)));
+        assert!(matches!(VAGFile::parse(b"VAG"), Err(ParseError::NotVAG)));
+        assert!(matches!(
+            VAGFile::parse(b"PPHD\0\0\0\x08"),
+            Err(ParseError::NotVAG)
+        ));
+        assert!(matches!(
+            VAGFile::parse(b"VAGp\0\0\0\x20"),
+            Err(ParseError::IncompleteVagFile {
+                expected_size: 0x30,
+                actual_size: 8
+            })
+        ));
+
+        // The body is shorter than the header says
+        let mut bytes = vagp_header(0x20, 0x40, 22050, &[0; 16]);
+        bytes.extend([0; 0x20]);
+        assert!(matches!(
+            VAGFile::parse(&bytes),
+            Err(ParseError::IncompleteVagFile {
+                expected_size: 0x70,
+                actual_size: 0x50
+            })
+        ));
+
+        // Interleaved files need their whole 0x800 bytes header and a chunk size
+        let stereo =
+            VAGFile::interleave_channels(&[mono_vag(8000, "a"), mono_vag(8000, "b")], 0x10)
+                .unwrap();
+        let mut bytes = vec![];
+        stereo.write_vag(&mut bytes).unwrap();
+        assert!(matches!(
+            VAGFile::parse(&bytes[..0x100]),
+            Err(ParseError::IncompleteVagFile {
+                actual_size: 0x100,
+                ..
+            })
+        ));
+        bytes[0x8..0xC].copy_from_slice(&[0; 4]);
+        assert!(matches!(
+            VAGFile::parse(&bytes),
+            Err(ParseError::InvalidInterleave { interleave: 0 })
+        ));
+    }
+
+    #[test]
+    fn written_vag_files_parse_back_the_same() {
+        let versions = [
+            VAGVersion::V2,
+            VAGVersion::V3,
+            VAGVersion::V4,
+            VAGVersion::V6,
+            VAGVersion::V20,
+            VAGVersion::Other(0x1234),
+        ];
+        for version in versions {
+            let vag = mono_vag(44100, "a_long_entry_name").with_version(version);
+            let mut bytes = vec![];
+            vag.write_vag(&mut bytes).unwrap();
+            assert_eq!(bytes.len(), VAGP_HEADER_SIZE + vag.body().len());
+
+            let parsed = VAGFile::parse(&bytes).unwrap();
+            assert_eq!(parsed.version(), version);
+            assert_eq!(parsed.frequency(), 44100);
+            assert_eq!(parsed.channels(), 1);
+            assert_eq!(parsed.name(), "a_long_entry_nam");
+            assert_eq!(parsed.body(), vag.body());
+        }
+    }
+
+    #[test]
+    fn from_wav_rejects_sample_rates_it_cant_resample_to() {
+        let audio = DecodedAudio {
+            samples: vec![0; 100],
+            sample_rate: 22050,
+            channels: 1,
+            loop_region: None,
+        };
+        let mut bytes = vec![];
+        wav::write_wav(&mut bytes, &audio).unwrap();
+
+        for sample_rate in [0, adpcm::MAX_SAMPLE_RATE + 1, 4_000_000_000] {
+            assert!(matches!(
+                VAGFile::from_wav(&bytes, Some(sample_rate)),
+                Err(ParseError::InvalidSampleRate { sample_rate: rate }) if rate == sample_rate
+            ));
+        }
+        let vag = VAGFile::from_wav(&bytes, Some(adpcm::MAX_SAMPLE_RATE)).unwrap();
+        assert_eq!(vag.frequency(), adpcm::MAX_SAMPLE_RATE);
+    }
+
+    /// A mono VAG file with this body
+    fn vag_with_body(frequency: u32, body: &[u8]) -> VAGFile<'static> {
+        let mut bytes = vagp_header(0x20, body.len() as u32, frequency, &[0; 16]);
+        bytes.extend(body);
+        VAGFile::parse(&bytes).unwrap().into_owned()
+    }
+
+    fn replace(bytes: &[u8], index: usize, vag: &VAGFile) -> Result<Vec<u8>, ParseError> {
+        let mut output = vec![];
+        PPHD8FileData::parse_from_bytes(bytes)?.replace_entry(index, vag, &mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn replace_entry_moves_the_next_bodies_keeping_their_alignment() {
+        let mut data = vec![1; 0x20];
+        data.resize(0x800, 0);
+        data.extend([2; 0x20]);
+        let bytes = bank(&[[0, 22050, 0x20], [0x800, 22050, 0x20]], &data);
+        assert_eq!(
+            PPHD8FileData::parse_from_bytes(&bytes)
+                .unwrap()
+                .body_alignment(),
+            0x800
+        );
+
+        let output = replace(&bytes, 0, &vag_with_body(11025, &[3; 0x30])).unwrap();
+        let file = PPHD8FileData::parse_from_bytes(&output).unwrap();
+        assert_eq!(file.entries()[0].frequency(), 11025);
+        assert_eq!(file.entries()[1].offset_from_data_start(), 0x1000);
+        assert_eq!(file.read_entry(0).unwrap().body(), [3; 0x30]);
+        assert_eq!(file.read_entry(1).unwrap().body(), [2; 0x20]);
+        assert_eq!(output.len(), bytes.len() + 0x800);
+    }
+
+    #[test]
+    fn replace_entry_rejects_bodies_shared_with_other_entries() {
+        // Entry 1 uses the same body as entry 0, entry 2 starts in the middle of it
+        let bytes = bank(
+            &[[0, 22050, 0x20], [0, 22050, 0x20], [0x10, 22050, 0x10]],
+            &[1; 0x20],
+        );
+        let vag = vag_with_body(22050, &[3; 0x40]);
+
+        for (index, other_index) in [(0, 1), (1, 0), (2, 0)] {
+            assert!(matches!(
+                replace(&bytes, index, &vag),
+                Err(ParseError::SharedEntryBody { entry_index, other_entry_index })
+                    if entry_index == index && other_entry_index == other_index
+            ));
+        }
+    }
+
+    #[test]
+    fn replace_entry_moves_bodies_starting_right_after_an_empty_one() {
+        // Entry 0 is empty, right where entry 1 starts
+        let bytes = bank(&[[0, 22050, 0], [0, 22050, 0x10]], &[2; 0x10]);
+
+        let output = replace(&bytes, 0, &vag_with_body(22050, &[3; 0x10])).unwrap();
+        let file = PPHD8FileData::parse_from_bytes(&output).unwrap();
+        assert_eq!(file.read_entry(0).unwrap().body(), [3; 0x10]);
+        assert_eq!(file.entries()[1].offset_from_data_start(), 0x10);
+        assert_eq!(file.read_entry(1).unwrap().body(), [2; 0x10]);
+    }
+
+    #[test]
+    fn replace_entry_to_file_leaves_nothing_behind_on_errors() {
+        let bytes = bank(&[[0, 22050, 0x10]], &[1; 0x10]);
+        let file = PPHD8FileData::parse_from_bytes(&bytes).unwrap();
+        let path = std::env::temp_dir().join(format!("pphd8_replace_{}.pphd8", std::process::id()));
+        let vag = vag_with_body(22050, &[3; 0x10]);
+
+        let result = file.replace_entry_to_file(5, &vag, &path);
+        assert!(matches!(
+            result,
+            Err(ParseError::EntryNotFound {
+                entry_index: 5,
+                n_files: 1
+            })
+        ));
+        assert!(!path.exists());
+        assert!(!path.with_extension("pphd8.tmp").exists());
+
+        // An existing file is only replaced once the new one is complete
+        fs::write(&path, b"old").unwrap();
+        assert!(file.replace_entry_to_file(5, &vag, &path).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        file.replace_entry_to_file(0, &vag, &path).unwrap();
+        let written = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(written, replace(&bytes, 0, &vag).unwrap());
+    }
+
+    #[test]
+    fn edit_entries_only_patches_their_words() {
+        let bytes = bank(&[[0, 22050, 0x10], [0x10, 22050, 0x10]], &[1; 0x20]);
+        let file = PPHD8FileData::parse_from_bytes(&bytes).unwrap();
+        let mut output = vec![];
+        file.edit_entries(&[(0, 8000)], &[1], &mut output).unwrap();
+
+        let mut expected = bytes.clone();
+        expected[0x84..0x88].copy_from_slice(&8000u32.to_le_bytes());
+        expected[0x90..0x9C].copy_from_slice(&[0xFF; 12]);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn edit_entries_to_file_leaves_nothing_behind_on_errors() {
+        let bytes = bank(&[[0, 22050, 0x10]], &[1; 0x10]);
+        let file = PPHD8FileData::parse_from_bytes(&bytes).unwrap();
+        let path = std::env::temp_dir().join(format!("pphd8_edit_{}.pphd8", std::process::id()));
+
+        for (frequencies, nulls) in [(&[(3, 8000)][..], &[][..]), (&[(0, 8000)], &[3])] {
+            let result = file.edit_entries_to_file(frequencies, nulls, &path);
+            assert!(matches!(
+                result,
+                Err(ParseError::EntryNotFound {
+                    entry_index: 3,
+                    n_files: 1
+                })
+            ));
+            assert!(!path.exists());
+            assert!(!path.with_extension("pphd8.tmp").exists());
+        }
     }
 }
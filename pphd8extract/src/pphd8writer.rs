@@ -0,0 +1,809 @@
+// PPHD8 writer, to build banks from VAG bodies or rebuild existing ones
+
+use std::borrow::Cow;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::mem::{size_of, size_of_val};
+use std::path::Path;
+
+use crate::adpcm;
+use crate::pphd8parser::{PPHD8FileData, PPHD8Header, ParseError, VAGFile};
+use crate::read_at::ReadAt;
+
+/// Word written in every field of a null entry
+const NULL_WORD: u32 = 0xFFFFFFFF;
+/// Words of a slot in the index: offset, frequency, size and padding
+const SLOT_WORDS: usize = 4;
+/// Size in bytes of a slot in the index
+const SLOT_SIZE: usize = SLOT_WORDS * size_of::<u32>();
+
+/// An entry to write in a PPHD8 file: a mono ADPCM body and its sample rate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PPHD8WriterEntry<'a> {
+    /// Sample rate in Hz
+    pub frequency: u32,
+    /// Raw ADPCM body, without any VAG header
+    pub body: Cow<'a, [u8]>,
+    /// Last word of the entry in the index. We don't know what it means, so entries read
+    /// from a PPHD8 file keep their own one.
+    pub padding: u32,
+}
+
+/// Builds a PPHD8 file from a header and an ordered list of index slots.
+///
+/// Writers made with [`PPHD8Writer::from_pphd8`] remember where everything was in that file:
+/// where the index starts, the bytes between sections, the words of null entries, where each
+/// body was and the bytes around them. While the entries keep their sizes and null entries stay
+/// null, the file is written back the same way, byte for byte if nothing changed. New entries
+/// go after the original data section.
+///
+/// Otherwise, or for writers made with [`PPHD8Writer::new`], the data section is built again
+/// with the bodies one after the other, in index order. The words at 0xC and 0x38 and the
+/// offsets of the entries always match the layout, every other header word is written as it is.
+#[derive(Debug, Clone, Default)]
+pub struct PPHD8Writer<'a> {
+    header: PPHD8Header,
+    // Slots of the index in order, `None` for null entries
+    slots: Vec<Option<PPHD8WriterEntry<'a>>>,
+    // Layout of the file this writer was read from, if any
+    layout: Option<SourceLayout>,
+}
+
+/// Where everything was in the file a writer was read from, so it can be written back the
+/// same way. See [`PPHD8Writer::from_pphd8`].
+#[derive(Debug, Clone)]
+struct SourceLayout {
+    // Absolute offset of the index section, the word at 0x38
+    index_offset: u64,
+    // Bytes between the metadata section and the index section
+    before_index: Vec<u8>,
+    // Absolute offset of the data section, the word at 0xC
+    data_offset: u64,
+    // Bytes between the last slot and the data section, too few to be a slot
+    index_tail: Vec<u8>,
+    // What each slot of the index had
+    slots: Vec<SourceSlot>,
+    // Bytes of the data section outside of every body, by their offset from its start
+    data_gaps: Vec<(u64, Vec<u8>)>,
+    // Size of the data section, up to the end of the file
+    data_size: u64,
+    // Alignment of the bodies, used for the ones added after them
+    alignment: u64,
+}
+
+/// A slot of the index of the file a writer was read from
+#[derive(Debug, Clone, Copy)]
+enum SourceSlot {
+    /// Offset from the start of the data section and size of the body of an entry
+    Entry { offset: u64, size: usize },
+    /// All the words of a null entry
+    Null([u32; SLOT_WORDS]),
+}
+
+/// Header of the bank written by [`merge_banks`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeHeader {
+    /// The header of the first bank, with its unknown words
+    #[default]
+    First,
+    /// A new header, see [`PPHD8Header::default`]
+    Default,
+}
+
+/// Where an entry of a merged bank comes from, see [`merge_banks`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MergedEntry {
+    /// Position of the bank in the list of merged banks
+    pub bank: usize,
+    /// Index of the entry in that bank
+    pub index: usize,
+    /// Index of the entry in the merged bank
+    pub new_index: usize,
+}
+
+impl<'a> PPHD8WriterEntry<'a> {
+    /// Entry with the given body, and the padding word used by null entries
+    pub fn new(frequency: u32, body: impl Into<Cow<'a, [u8]>>) -> Self {
+        PPHD8WriterEntry {
+            frequency,
+            body: body.into(),
+            padding: NULL_WORD,
+        }
+    }
+
+    /// Entry with the body and sample rate of a mono VAG file.
+    ///
+    /// Returns [`ParseError::NotMono`] if the file has more than one channel.
+    pub fn from_vag(vag: VAGFile<'a>) -> Result<Self, ParseError> {
+        if vag.channels() != 1 {
+            return Err(ParseError::NotMono {
+                channels: vag.channels(),
+            });
+        }
+
+        Ok(Self::new(vag.frequency(), vag.into_body()))
+    }
+}
+
+impl<'a> PPHD8Writer<'a> {
+    /// Writer with no entries, using `header` for the metadata section
+    pub fn new(header: PPHD8Header) -> Self {
+        PPHD8Writer {
+            header,
+            slots: vec![],
+            layout: None,
+        }
+    }
+
+    /// Writer with the header and all the entries of a PPHD8 file, null entries included,
+    /// and the layout of the file so it can be written back the same way.
+    ///
+    /// Bodies are copied, so this reads the whole file and is subject to its
+    /// [`ParseLimits`](crate::pphd8parser::ParseLimits).
+    pub fn from_pphd8<S: ReadAt>(
+        file: &PPHD8FileData<S>,
+    ) -> Result<PPHD8Writer<'static>, ParseError> {
+        let slot_count = file
+            .entries()
+            .last()
+            .map(|entry| entry.slot_index() + 1)
+            .unwrap_or(0)
+            .max(file.header().index_slot_count() as usize);
+        let mut slots = vec![None; slot_count];
+
+        for (entry, vag) in file.entries().iter().zip(file.get_vag_files()?) {
+            slots[entry.slot_index()] = Some(PPHD8WriterEntry {
+                frequency: entry.frequency(),
+                body: vag.into_body(),
+                padding: entry.padding(),
+            });
+        }
+
+        Ok(PPHD8Writer {
+            header: file.header().clone(),
+            layout: SourceLayout::read(file, slot_count)?,
+            slots,
+        })
+    }
+
+    /// Header written in the metadata section, the offsets in it are replaced when writing
+    pub fn header(&self) -> &PPHD8Header {
+        &self.header
+    }
+
+    /// Slots of the index in order, `None` for null entries
+    pub fn slots(&self) -> &[Option<PPHD8WriterEntry<'a>>] {
+        &self.slots
+    }
+
+    /// Mutable slots of the index, to edit, reorder or remove them
+    pub fn slots_mut(&mut self) -> &mut Vec<Option<PPHD8WriterEntry<'a>>> {
+        &mut self.slots
+    }
+
+    /// Add an entry at the end of the index
+    pub fn push_entry(&mut self, entry: PPHD8WriterEntry<'a>) {
+        self.slots.push(Some(entry));
+    }
+
+    /// Add a null entry at the end of the index
+    pub fn push_null(&mut self) {
+        self.slots.push(None);
+    }
+
+    /// Number of (non null) entries, the same way [`PPHD8FileData::n_files`] counts them
+    pub fn entry_count(&self) -> usize {
+        self.slots.iter().flatten().count()
+    }
+
+    /// Entry at the given position, not counting null entries
+    pub fn entry(&self, index: usize) -> Option<&PPHD8WriterEntry<'a>> {
+        self.slots.iter().flatten().nth(index)
+    }
+
+    /// Mutable entry at the given position, not counting null entries
+    pub fn entry_mut(&mut self, index: usize) -> Option<&mut PPHD8WriterEntry<'a>> {
+        self.slots.iter_mut().flatten().nth(index)
+    }
+
+    /// Change the sample rate of an entry, without touching its body
+    pub fn set_frequency(&mut self, index: usize, frequency: u32) -> Result<(), ParseError> {
+        let not_found = self.not_found(index);
+        let entry = self.entry_mut(index).ok_or(not_found)?;
+        entry.frequency = frequency;
+        Ok(())
+    }
+
+    /// Turn an entry into a null entry, so the ones after it keep their slot in the index.
+    /// Returns the entry.
+    pub fn null_entry(&mut self, index: usize) -> Result<PPHD8WriterEntry<'a>, ParseError> {
+        let not_found = self.not_found(index);
+        self.slots
+            .iter_mut()
+            .filter(|slot| slot.is_some())
+            .nth(index)
+            .and_then(Option::take)
+            .ok_or(not_found)
+    }
+
+    /// Remove an entry and its slot from the index, moving the ones after it back.
+    /// Returns the entry.
+    pub fn remove_entry(&mut self, index: usize) -> Result<PPHD8WriterEntry<'a>, ParseError> {
+        let position = self.slot_position(index)?;
+        self.slots.remove(position).ok_or(self.not_found(index))
+    }
+
+    /// Reorder the entries: `order` lists the current index of every entry, in their new order.
+    /// Null entries keep their slots, the others are placed in the remaining ones.
+    ///
+    /// Returns [`ParseError::InvalidEntryOrder`] if `order` doesn't have every entry exactly once.
+    pub fn reorder_entries(&mut self, order: &[usize]) -> Result<(), ParseError> {
+        let entry_count = self.entry_count();
+        let mut seen = vec![false; entry_count];
+        let is_permutation = order.len() == entry_count
+            && order
+                .iter()
+                .all(|&index| index < entry_count && !std::mem::replace(&mut seen[index], true));
+        if !is_permutation {
+            return Err(ParseError::InvalidEntryOrder { entry_count });
+        }
+
+        let positions: Vec<usize> = (0..self.slots.len())
+            .filter(|&position| self.slots[position].is_some())
+            .collect();
+        let mut entries: Vec<_> = positions
+            .iter()
+            .map(|&position| self.slots[position].take())
+            .collect();
+        for (&position, &index) in positions.iter().zip(order) {
+            self.slots[position] = entries[index].take();
+        }
+
+        Ok(())
+    }
+
+    /// Write the PPHD8 file.
+    ///
+    /// New writers, and the ones whose entries changed too much to keep the layout of the file
+    /// they were read from, write this:
+    ///
+    /// | Offset                | Content                                             |
+    /// |-----------------------|-----------------------------------------------------|
+    /// | 0x00                  | Metadata section, with the offsets updated          |
+    /// | 0x40                  | Bytes before the index in the original file, if any |
+    /// | Index                 | Index header, as it is                              |
+    /// | Index + 0x40          | 16 bytes per slot: offset, frequency, size, padding |
+    /// | Index + 0x40 + 16 * n | Bodies, each padded with zeros to the alignment     |
+    ///
+    /// The alignment is the one of the bodies in the original file, or whole ADPCM blocks.
+    ///
+    /// Returns an error of kind [`io::ErrorKind::InvalidInput`] if the file would be
+    /// too big for the 32 bits offsets of the format.
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let too_big = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Too much data for a PPHD8 file",
+            )
+        };
+        let to_u32 = |value: u64| u32::try_from(value).map_err(|_| too_big());
+
+        // The original data section is kept if the entries still fit in it, and the index
+        // too if there are no new slots
+        let layout = self.layout.as_ref();
+        let kept_data = layout.filter(|layout| layout.fits(&self.slots));
+        let kept_index = kept_data.filter(|layout| layout.slots.len() == self.slots.len());
+
+        let index_offset = layout.map_or(size_of_val(self.header.words()) as u64, |layout| {
+            layout.index_offset
+        });
+        let entries_offset = index_offset + size_of_val(self.header.index_header()) as u64;
+        let data_offset = match kept_index {
+            Some(layout) => layout.data_offset,
+            None => entries_offset + (self.slots.len() * SLOT_SIZE) as u64,
+        };
+
+        // Offset of each body from the start of the data section
+        let alignment = layout.map_or(adpcm::BLOCK_SIZE as u64, |layout| layout.alignment);
+        let mut next_offset =
+            kept_data.map_or(0, |layout| layout.data_size.next_multiple_of(alignment));
+        let mut offsets = Vec::with_capacity(self.slots.len());
+        for (position, slot) in self.slots.iter().enumerate() {
+            let source_slot = kept_data.and_then(|layout| layout.slots.get(position));
+            let offset = match (source_slot, slot) {
+                (Some(SourceSlot::Entry { offset, .. }), _) => *offset,
+                (_, Some(entry)) => {
+                    let offset = next_offset;
+                    next_offset = (offset + entry.body.len() as u64).next_multiple_of(alignment);
+                    offset
+                }
+                (_, None) => 0,
+            };
+            offsets.push(offset);
+        }
+
+        let mut words = *self.header.words();
+        words[3] = to_u32(data_offset)?;
+        words[14] = to_u32(index_offset)?;
+        write_words(writer, &words)?;
+        writer.write_all(layout.map_or(&[], |layout| &layout.before_index))?;
+        write_words(writer, self.header.index_header())?;
+
+        let mut index = Vec::with_capacity(self.slots.len() * SLOT_SIZE);
+        for (position, (slot, offset)) in self.slots.iter().zip(&offsets).enumerate() {
+            let slot_words = match slot {
+                Some(entry) => [
+                    to_u32(*offset)?,
+                    entry.frequency,
+                    to_u32(entry.body.len() as u64)?,
+                    entry.padding,
+                ],
+                None => match layout.and_then(|layout| layout.slots.get(position)) {
+                    Some(SourceSlot::Null(words)) => *words,
+                    _ => [NULL_WORD; SLOT_WORDS],
+                },
+            };
+            index.extend(slot_words.iter().flat_map(|word| word.to_le_bytes()));
+        }
+        if let Some(layout) = kept_index {
+            // If the last slot was cut short by the data section, it's cut the same way
+            index.extend_from_slice(&layout.index_tail);
+            index.truncate((data_offset - entries_offset) as usize);
+        }
+        writer.write_all(&index)?;
+
+        // Bodies and the bytes between them, in the order they go in the file
+        let mut pieces: Vec<(u64, &[u8])> = self
+            .slots
+            .iter()
+            .zip(&offsets)
+            .filter_map(|(slot, offset)| Some((*offset, slot.as_ref()?.body.as_ref())))
+            .collect();
+        if let Some(layout) = kept_data {
+            pieces.extend(
+                layout
+                    .data_gaps
+                    .iter()
+                    .map(|(offset, bytes)| (*offset, bytes.as_slice())),
+            );
+        }
+        pieces.sort_by_key(|(offset, _)| *offset);
+
+        let mut position = 0;
+        for (offset, bytes) in pieces {
+            write_zeros(writer, offset.saturating_sub(position))?;
+            // Bodies shared by several entries are only written once
+            let written = position.saturating_sub(offset).min(bytes.len() as u64);
+            writer.write_all(&bytes[written as usize..])?;
+            position = position.max(offset + bytes.len() as u64);
+        }
+        if kept_index.is_none() {
+            write_zeros(writer, position.next_multiple_of(alignment) - position)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the PPHD8 file to disk, see [`PPHD8Writer::write`]
+    pub fn write_to_file(&self, filepath: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(fs::File::create(filepath)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Write the PPHD8 file to a vector, see [`PPHD8Writer::write`]
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![];
+        self.write(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Position in `slots` of an entry, not counting null entries
+    fn slot_position(&self, index: usize) -> Result<usize, ParseError> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_some())
+            .nth(index)
+            .map(|(position, _)| position)
+            .ok_or(self.not_found(index))
+    }
+
+    /// Error for an entry that is not in the index
+    fn not_found(&self, index: usize) -> ParseError {
+        ParseError::EntryNotFound {
+            entry_index: index,
+            n_files: self.entry_count(),
+        }
+    }
+}
+
+/// Merge several PPHD8 files into one, with all the entries of the first one followed by
+/// all the entries of the next ones. Null entries are kept, so the entries of the first file
+/// keep their index.
+///
+/// With [`MergeHeader::First`] the layout of the first file is kept too, like in
+/// [`PPHD8Writer::from_pphd8`], so the data it has in its header and around its sections
+/// stays where it was. The entries of the other files go after its data section.
+///
+/// Returns the writer for the merged file, and where each entry of each file ends up in it.
+pub fn merge_banks<S: ReadAt>(
+    banks: &[PPHD8FileData<S>],
+    header: MergeHeader,
+) -> Result<(PPHD8Writer<'static>, Vec<MergedEntry>), ParseError> {
+    let mut merged = PPHD8Writer::new(PPHD8Header::default());
+    let mut mapping = vec![];
+    for (bank, file) in banks.iter().enumerate() {
+        let first_index = merged.entry_count();
+        let writer = PPHD8Writer::from_pphd8(file)?;
+        if bank == 0 && header == MergeHeader::First {
+            merged = writer;
+        } else {
+            merged.slots.extend(writer.slots);
+        }
+        mapping.extend((0..file.n_files()).map(|index| MergedEntry {
+            bank,
+            index,
+            new_index: first_index + index,
+        }));
+    }
+
+    Ok((merged, mapping))
+}
+
+impl SourceLayout {
+    /// Read the layout of a file with `slot_count` slots in its index, or `None` if its
+    /// sections overlap and can't be written back in the same place
+    fn read<S: ReadAt>(
+        file: &PPHD8FileData<S>,
+        slot_count: usize,
+    ) -> Result<Option<Self>, ParseError> {
+        let header = file.header();
+        let source = file.source();
+        let metadata_size = size_of_val(header.words()) as u64;
+        let index_offset = header.index_offset() as u64;
+        let entries_offset = header.index_entries_offset();
+        let data_offset = header.data_offset() as u64;
+        let slots_end = entries_offset + (slot_count * SLOT_SIZE) as u64;
+        if index_offset < metadata_size
+            || data_offset < entries_offset
+            || data_offset > file.file_size()
+        {
+            return Ok(None);
+        }
+
+        // The last slot can run into the data section, or even past the end of the file
+        let index = read_bytes(source, entries_offset, slots_end)?;
+        let mut slots: Vec<SourceSlot> = index
+            .chunks_exact(SLOT_SIZE)
+            .map(|slot| {
+                let mut words = [0u32; SLOT_WORDS];
+                for (word, bytes) in words.iter_mut().zip(slot.chunks_exact(size_of::<u32>())) {
+                    *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                }
+                SourceSlot::Null(words)
+            })
+            .collect();
+        for entry in file.entries() {
+            slots[entry.slot_index()] = SourceSlot::Entry {
+                offset: entry.offset_from_data_start() as u64,
+                size: entry.size() as usize,
+            };
+        }
+
+        // Everything in the data section that is not a body, like padding or trailing bytes
+        let data_size = file.file_size() - data_offset;
+        let mut bodies: Vec<(u64, u64)> = file
+            .entries()
+            .iter()
+            .map(|entry| {
+                let offset = entry.offset_from_data_start() as u64;
+                (offset, offset + entry.size() as u64)
+            })
+            .collect();
+        bodies.sort_unstable();
+        let mut data_gaps = vec![];
+        let mut position = 0;
+        for (start, end) in bodies.into_iter().chain([(data_size, data_size)]) {
+            if start > position {
+                let bytes = read_bytes(source, data_offset + position, data_offset + start)?;
+                data_gaps.push((position, bytes));
+            }
+            position = position.max(end);
+        }
+
+        Ok(Some(SourceLayout {
+            index_offset,
+            before_index: read_bytes(source, metadata_size, index_offset)?,
+            data_offset,
+            index_tail: read_bytes(source, slots_end, data_offset.max(slots_end))?,
+            slots,
+            data_gaps,
+            data_size,
+            alignment: file.body_alignment(),
+        }))
+    }
+
+    /// Whether these slots can be written in the original data section: entries where there
+    /// were entries of the same size, null entries where there were null entries, and maybe
+    /// more slots after them. Bodies that shared bytes in the file must still agree on them.
+    fn fits(&self, slots: &[Option<PPHD8WriterEntry>]) -> bool {
+        if slots.len() < self.slots.len() {
+            return false;
+        }
+
+        let mut bodies = vec![];
+        for (source_slot, slot) in self.slots.iter().zip(slots) {
+            match (source_slot, slot) {
+                (SourceSlot::Entry { offset, size }, Some(entry)) if entry.body.len() == *size => {
+                    bodies.push((*offset, &entry.body[..]))
+                }
+                (SourceSlot::Null(_), None) => {}
+                _ => return false,
+            }
+        }
+
+        bodies.sort_by_key(|(offset, _)| *offset);
+        bodies.iter().enumerate().all(|(i, &(offset, body))| {
+            let end = offset + body.len() as u64;
+            bodies[i + 1..]
+                .iter()
+                .take_while(|(other_offset, _)| *other_offset < end)
+                .all(|&(other_offset, other)| {
+                    let start = (other_offset - offset) as usize;
+                    let shared = (body.len() - start).min(other.len());
+                    body[start..start + shared] == other[..shared]
+                })
+        })
+    }
+}
+
+/// Read the bytes of a file between two absolute offsets, leaving zeros past its end
+fn read_bytes<S: ReadAt>(source: &S, start: u64, end: u64) -> Result<Vec<u8>, ParseError> {
+    let mut bytes = vec![0u8; end.saturating_sub(start) as usize];
+    source.read_at(&mut bytes, start)?;
+    Ok(bytes)
+}
+
+/// Write `count` zeros
+fn write_zeros<W: Write>(writer: &mut W, count: u64) -> io::Result<()> {
+    io::copy(&mut io::Read::take(io::repeat(0), count), writer)?;
+    Ok(())
+}
+
+/// Write little endian words, like every word of a PPHD8 file
+fn write_words<W: Write>(writer: &mut W, words: &[u32]) -> io::Result<()> {
+    for word in words {
+        writer.write_all(&word.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NULL_SLOT: [u32; SLOT_WORDS] = [NULL_WORD, NULL_WORD, NULL_WORD, 0x1234];
+
+    /// A bank with the index at `index_offset`, `before_index` between it and the metadata
+    /// section, `index_tail` between the slots and the data section and unknown header words
+    fn bank(
+        index_offset: usize,
+        before_index: &[u8],
+        slots: &[[u32; SLOT_WORDS]],
+        index_tail: &[u8],
+        data: &[u8],
+    ) -> Vec<u8> {
+        assert_eq!(index_offset, 0x40 + before_index.len());
+        let data_offset = index_offset + 0x40 + slots.len() * SLOT_SIZE + index_tail.len();
+
+        let mut words: Vec<u32> = (0..16).map(|word| 0xA0 + word).collect();
+        words[0] = u32::from_le_bytes(*b"PPHD");
+        words[1] = 8;
+        words[3] = data_offset as u32;
+        words[14] = index_offset as u32;
+        words.extend((0..16).map(|word| 0xB0 + word));
+        words.extend(slots.iter().flatten());
+
+        let mut bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        bytes.splice(0x40..0x40, before_index.iter().copied());
+        bytes.extend_from_slice(index_tail);
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn body(byte: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| byte.wrapping_add(i as u8)).collect()
+    }
+
+    fn bodies(bytes: &[u8]) -> Vec<(usize, u32, Vec<u8>)> {
+        let file = PPHD8FileData::parse_from_bytes(bytes).unwrap();
+        file.entries()
+            .iter()
+            .zip(file.get_vag_files().unwrap())
+            .map(|(entry, vag)| {
+                (
+                    entry.slot_index(),
+                    entry.frequency(),
+                    vag.into_body().into_owned(),
+                )
+            })
+            .collect()
+    }
+
+    /// Index at 0x100 after some bytes, a null slot with a padding word, bodies out of index
+    /// order with bytes between them, two entries sharing a body and bytes after the last one
+    fn non_canonical_bank() -> Vec<u8> {
+        let mut data = body(0x20, 0x30);
+        data.extend([0x55; 0x10]);
+        data.extend(body(0x80, 0x20));
+        data.extend(b"trailer");
+
+        bank(
+            0x100,
+            &body(0x10, 0xC0),
+            &[
+                [0x40, 22050, 0x20, 0],
+                NULL_SLOT,
+                [0, 11025, 0x30, 7],
+                [0x40, 44100, 0x20, 0],
+            ],
+            &[0xEE; 8],
+            &data,
+        )
+    }
+
+    #[test]
+    fn from_pphd8_writes_non_canonical_banks_back_byte_for_byte() {
+        let original = non_canonical_bank();
+        let file = PPHD8FileData::parse_from_bytes(&original).unwrap();
+        let written = PPHD8Writer::from_pphd8(&file).unwrap().to_bytes().unwrap();
+
+        assert_eq!(written, original);
+    }
+
+    #[test]
+    fn from_pphd8_writes_a_last_slot_cut_by_the_data_section_back_byte_for_byte() {
+        // The last slot only has 13 bytes before the data section, so the last 3 bytes of its
+        // padding word are the first ones of the body
+        let mut last_slot: Vec<u8> = [0u32, 11025, 0x10]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        last_slot.push(0x99);
+        let original = bank(
+            0x40,
+            &[],
+            &[[0, 22050, 0x10, 0]],
+            &last_slot,
+            &body(0x20, 0x10),
+        );
+
+        let file = PPHD8FileData::parse_from_bytes(&original).unwrap();
+        assert_eq!(file.header().index_slot_count(), 2);
+        let written = PPHD8Writer::from_pphd8(&file).unwrap().to_bytes().unwrap();
+
+        assert_eq!(written, original);
+    }
+
+    #[test]
+    fn changing_a_frequency_only_changes_its_word() {
+        let original = non_canonical_bank();
+        let file = PPHD8FileData::parse_from_bytes(&original).unwrap();
+        let mut writer = PPHD8Writer::from_pphd8(&file).unwrap();
+        writer.set_frequency(1, 8000).unwrap();
+
+        let mut expected = original.clone();
+        let word = 0x140 + 2 * SLOT_SIZE + 4;
+        expected[word..word + 4].copy_from_slice(&8000u32.to_le_bytes());
+        assert_eq!(writer.to_bytes().unwrap(), expected);
+    }
+
+    #[test]
+    fn new_entries_go_after_the_original_data_section() {
+        let original = non_canonical_bank();
+        let file = PPHD8FileData::parse_from_bytes(&original).unwrap();
+        let mut writer = PPHD8Writer::from_pphd8(&file).unwrap();
+        writer.push_entry(PPHD8WriterEntry::new(32000, body(0x40, 0x10)));
+        let written = writer.to_bytes().unwrap();
+
+        let old_data = &original[file.header().data_offset() as usize..];
+        let new_file = PPHD8FileData::parse_from_bytes(&written).unwrap();
+        let new_data = &written[new_file.header().data_offset() as usize..];
+        assert_eq!(&new_data[..old_data.len()], old_data);
+        assert_eq!(written[0x40..0x140], original[0x40..0x140]);
+
+        let mut expected = bodies(&original);
+        expected.push((4, 32000, body(0x40, 0x10)));
+        assert_eq!(bodies(&written), expected);
+        assert_eq!(new_file.entries()[3].offset_from_data_start(), 0x80);
+    }
+
+    #[test]
+    fn resized_entries_are_packed_again() {
+        let original = non_canonical_bank();
+        let file = PPHD8FileData::parse_from_bytes(&original).unwrap();
+        let mut writer = PPHD8Writer::from_pphd8(&file).unwrap();
+        writer.entry_mut(0).unwrap().body = body(0x70, 0x50).into();
+        let written = writer.to_bytes().unwrap();
+
+        let new_file = PPHD8FileData::parse_from_bytes(&written).unwrap();
+        assert_eq!(new_file.header().index_offset(), 0x100);
+        assert_eq!(written[0x40..0x100], original[0x40..0x100]);
+        assert_eq!(
+            written[0x140 + SLOT_SIZE..0x140 + 2 * SLOT_SIZE],
+            original[0x150..0x160]
+        );
+
+        let mut expected = bodies(&original);
+        expected[0].2 = body(0x70, 0x50);
+        assert_eq!(bodies(&written), expected);
+    }
+
+    #[test]
+    fn merged_banks_keep_the_layout_and_null_slots_of_the_first_one() {
+        let first = non_canonical_bank();
+        let second = bank(
+            0x40,
+            &[],
+            &[NULL_SLOT, [0, 32000, 0x10, 0], [0x10, 16000, 0x20, 0]],
+            &[],
+            &body(0x60, 0x30),
+        );
+        let banks = [
+            PPHD8FileData::parse_from_bytes(&first).unwrap(),
+            PPHD8FileData::parse_from_bytes(&second).unwrap(),
+        ];
+
+        let (merged, mapping) = merge_banks(&banks, MergeHeader::First).unwrap();
+        let new_indices: Vec<(usize, usize, usize)> = mapping
+            .iter()
+            .map(|entry| (entry.bank, entry.index, entry.new_index))
+            .collect();
+        assert_eq!(
+            new_indices,
+            [(0, 0, 0), (0, 1, 1), (0, 2, 2), (1, 0, 3), (1, 1, 4)]
+        );
+
+        let written = merged.to_bytes().unwrap();
+        assert_eq!(written[0x10..0x38], first[0x10..0x38]);
+        assert_eq!(written[0x3C..0x140], first[0x3C..0x140]);
+
+        let merged_bodies = bodies(&written);
+        let mut expected = bodies(&first);
+        expected.extend(
+            bodies(&second)
+                .into_iter()
+                .map(|(slot, frequency, body)| (slot + 4, frequency, body)),
+        );
+        assert_eq!(merged_bodies, expected);
+        for entry in &mapping {
+            let bank_bodies = bodies(banks[entry.bank].source());
+            assert_eq!(merged_bodies[entry.new_index].2, bank_bodies[entry.index].2);
+        }
+    }
+
+    #[test]
+    fn new_writers_use_the_canonical_layout() {
+        let mut writer = PPHD8Writer::new(PPHD8Header::default());
+        writer.push_entry(PPHD8WriterEntry::new(22050, body(0, 0x18)));
+        writer.push_null();
+        writer.push_entry(PPHD8WriterEntry::new(11025, body(0x30, 0x10)));
+        let written = writer.to_bytes().unwrap();
+
+        let file = PPHD8FileData::parse_from_bytes(&written).unwrap();
+        assert_eq!(file.header().index_offset(), 0x40);
+        assert_eq!(file.header().data_offset(), 0x80 + 3 * SLOT_SIZE as u32);
+        let offsets: Vec<u32> = file
+            .entries()
+            .iter()
+            .map(|entry| entry.offset_from_data_start())
+            .collect();
+        assert_eq!(offsets, [0, 0x20]);
+        assert_eq!(written.len(), 0xB0 + 0x30);
+        assert_eq!(
+            PPHD8Writer::from_pphd8(&file).unwrap().to_bytes().unwrap(),
+            written
+        );
+    }
+}
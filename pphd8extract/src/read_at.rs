@@ -0,0 +1,163 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Mutex;
+
+/// Positional reads: read bytes at a given offset without going through a shared cursor.
+///
+/// The standard library only provides this through platform specific extension traits
+/// (`std::os::unix::fs::FileExt::read_at` and `std::os::windows::fs::FileExt::seek_read`),
+/// which also behave slightly different: on Windows the file cursor is moved, and both of
+/// them can return less bytes than requested. This trait hides those differences so the parser
+/// behaves the same on every platform.
+pub trait ReadAt {
+    /// Read up to `buf.len()` bytes starting at `offset`. Returns how many bytes were read.
+    ///
+    /// Unlike [`std::io::Read::read`], this will keep reading until the buffer is full or
+    /// the end of the source is reached, so a short count always means end of file.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Total size in bytes of this source
+    fn len(&self) -> io::Result<u64>;
+
+    /// Whether this source has no bytes at all
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+impl ReadAt for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut total = 0;
+        while total < buf.len() {
+            match read_at_once(self, &mut buf[total..], offset + total as u64) {
+                Ok(0) => break,
+                Ok(n) => total += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(total)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl ReadAt for [u8] {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let start = match usize::try_from(offset) {
+            Ok(start) if start < self.len() => start,
+            _ => return Ok(0),
+        };
+
+        let n = buf.len().min(self.len() - start);
+        buf[..n].copy_from_slice(&self[start..start + n]);
+        Ok(n)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(<[u8]>::len(self) as u64)
+    }
+}
+
+impl ReadAt for Vec<u8> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.as_slice().read_at(buf, offset)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(Vec::len(self) as u64)
+    }
+}
+
+impl ReadAt for memmap2::Mmap {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(<[u8]>::len(self) as u64)
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for &T {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        (**self).len()
+    }
+}
+
+/// Adapter to use any `Read + Seek` type as a [`ReadAt`] source.
+///
+/// Positional reads take `&self`, so the reader is kept behind a mutex and every read
+/// seeks to the requested offset before reading.
+#[derive(Debug)]
+pub struct SeekReader<R> {
+    reader: Mutex<R>,
+}
+
+impl<R: Read + Seek> SeekReader<R> {
+    pub fn new(reader: R) -> Self {
+        SeekReader {
+            reader: Mutex::new(reader),
+        }
+    }
+
+    /// Get back the wrapped reader
+    pub fn into_inner(self) -> R {
+        self.reader
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<R: Read + Seek> ReadAt for SeekReader<R> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut reader = self
+            .reader
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reader.seek(SeekFrom::Start(offset))?;
+
+        let mut total = 0;
+        while total < buf.len() {
+            match reader.read(&mut buf[total..]) {
+                Ok(0) => break,
+                Ok(n) => total += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(total)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        let mut reader = self
+            .reader
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reader.seek(SeekFrom::End(0))
+    }
+}
+
+#[cfg(unix)]
+fn read_at_once(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at_once(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_at_once(mut file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read(buf)
+}
@@ -0,0 +1,83 @@
+// Sample rate conversion for imported audio.
+//
+// A windowed sinc resampler: each output sample is a weighted sum of the input samples around
+// it, with a low pass filter at the lowest of both Nyquist frequencies to avoid aliasing.
+
+use std::f64::consts::PI;
+
+use crate::adpcm::{DecodedAudio, LoopRegion, MAX_SAMPLE_RATE};
+
+/// Input samples on each side of an output sample used to compute it, at the input rate.
+/// More means a sharper filter and slower conversion.
+const HALF_TAPS: f64 = 16.0;
+
+/// Convert audio to another sample rate, moving its loop points to match.
+///
+/// Returns the same audio if the rates are already the same or any of them is 0. Rates
+/// over [`MAX_SAMPLE_RATE`] are lowered to it, since the output buffer depends on them.
+pub fn resample(audio: &DecodedAudio, sample_rate: u32) -> DecodedAudio {
+    let sample_rate = sample_rate.min(MAX_SAMPLE_RATE);
+    if audio.sample_rate == sample_rate || audio.sample_rate == 0 || sample_rate == 0 {
+        return audio.clone();
+    }
+
+    let ratio = sample_rate as f64 / audio.sample_rate as f64;
+    let channels = audio.channels.max(1) as usize;
+    let input_frames = audio.frame_count();
+    let output_frames = (input_frames as f64 * ratio).round() as usize;
+
+    // When going down, the filter has to cut at the new Nyquist frequency, so it gets wider
+    let cutoff = ratio.min(1.0);
+    let half_width = HALF_TAPS / cutoff;
+
+    let mut samples = vec![0i16; output_frames * channels];
+    for frame in 0..output_frames {
+        let position = frame as f64 / ratio;
+        let first = (position - half_width).ceil().max(0.0) as usize;
+        let last = ((position + half_width).floor() as usize).min(input_frames.saturating_sub(1));
+
+        let mut sums = vec![0.0; channels];
+        for input_frame in first..=last {
+            let distance = position - input_frame as f64;
+            let weight = cutoff * sinc(cutoff * distance) * hann(distance / half_width);
+            for (channel, sum) in sums.iter_mut().enumerate() {
+                *sum += audio.samples[input_frame * channels + channel] as f64 * weight;
+            }
+        }
+
+        for (channel, sum) in sums.into_iter().enumerate() {
+            samples[frame * channels + channel] =
+                sum.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+        }
+    }
+
+    let scale = |position: usize| ((position as f64 * ratio).round() as usize).min(output_frames);
+    DecodedAudio {
+        samples,
+        sample_rate,
+        channels: audio.channels,
+        loop_region: audio.loop_region.and_then(|loop_region| {
+            let start = scale(loop_region.start);
+            let end = scale(loop_region.end);
+            (start < end).then_some(LoopRegion { start, end })
+        }),
+    }
+}
+
+/// Normalized sinc function, sin(pi x) / (pi x)
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Hann window, going from 1 at 0 to 0 at -1 and 1
+fn hann(x: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        0.0
+    } else {
+        0.5 * (1.0 + (PI * x).cos())
+    }
+}
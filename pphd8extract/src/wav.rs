@@ -0,0 +1,509 @@
+// RIFF/WAVE reader and writer for decoded VAG files
+
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::adpcm::{DecodedAudio, LoopRegion, BITS_PER_SAMPLE, MIDI_UNITY_NOTE};
+use crate::pphd8parser::ParseError;
+
+/// Format tag for plain PCM in the `fmt ` chunk
+const WAVE_FORMAT_PCM: u16 = 1;
+/// Format tag for floating point samples
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+/// Format tag for files with the real format in the first two bytes of a GUID, after the
+/// usual fields of the `fmt ` chunk
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+/// Cue point ids used for the start and end of the loop
+const LOOP_START_CUE_ID: u32 = 1;
+const LOOP_END_CUE_ID: u32 = 2;
+
+/// Write decoded audio as a 16 bits PCM WAV file.
+///
+/// If the audio has a loop region, it's written as a forward loop in a `smpl` chunk, and its
+/// start and end as cue points in a `cue ` chunk, so samplers loop it the same way the SPU did.
+pub fn write_wav<W: Write>(writer: &mut W, audio: &DecodedAudio) -> io::Result<()> {
+    let too_long = || io::Error::new(io::ErrorKind::InvalidInput, "Too much audio for a WAV file");
+    let channels = audio.channels;
+    let block_align = channels.checked_mul(BITS_PER_SAMPLE / 8).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Too many channels for a WAV file",
+        )
+    })?;
+    // The sample rate comes from the file, it can be way too high for this field
+    let byte_rate = audio
+        .sample_rate
+        .checked_mul(block_align as u32)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Sample rate too high for a WAV file",
+            )
+        })?;
+    let data_size = u32::try_from(audio.samples.len() * (BITS_PER_SAMPLE / 8) as usize)
+        .map_err(|_| too_long())?;
+
+    let loop_chunks = match audio.loop_region {
+        Some(loop_region) => loop_chunks(loop_region, audio.sample_rate)?,
+        None => vec![],
+    };
+
+    // RIFF header, its size counts everything after the size field
+    let fmt_chunk_size: u32 = 16;
+    let riff_size = u32::try_from(loop_chunks.len())
+        .ok()
+        .and_then(|loop_size| (4 + 8 + fmt_chunk_size + 8).checked_add(loop_size))
+        .and_then(|size| size.checked_add(data_size))
+        .ok_or_else(too_long)?;
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&riff_size.to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    // Format chunk
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&fmt_chunk_size.to_le_bytes())?;
+    writer.write_all(&WAVE_FORMAT_PCM.to_le_bytes())?;
+    writer.write_all(&channels.to_le_bytes())?;
+    writer.write_all(&audio.sample_rate.to_le_bytes())?;
+    writer.write_all(&byte_rate.to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+    // Sample data
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())?;
+    for sample in &audio.samples {
+        writer.write_all(&sample.to_le_bytes())?;
+    }
+
+    // Loop information goes after the data, where players that don't know it won't mind
+    writer.write_all(&loop_chunks)?;
+
+    Ok(())
+}
+
+/// Write decoded audio to a WAV file in disk
+pub fn write_wav_file(filepath: &Path, audio: &DecodedAudio) -> io::Result<()> {
+    let mut writer = BufWriter::new(fs::File::create(filepath)?);
+    write_wav(&mut writer, audio)?;
+    writer.flush()
+}
+
+/// Build the `cue ` and `smpl` chunks for a loop region
+fn loop_chunks(loop_region: LoopRegion, sample_rate: u32) -> io::Result<Vec<u8>> {
+    let to_u32 = |position: usize| {
+        u32::try_from(position).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Loop point out of range for a WAV file",
+            )
+        })
+    };
+    let start = to_u32(loop_region.start)?;
+    // WAV loop ends are inclusive, ours are exclusive
+    let end = to_u32(loop_region.end.saturating_sub(1))?;
+
+    let mut chunks = vec![];
+
+    // Cue points: number of points, then (id, position, chunk id, chunk start, block start, sample offset)
+    let cue_points = [(LOOP_START_CUE_ID, start), (LOOP_END_CUE_ID, end)];
+    chunks.extend_from_slice(b"cue ");
+    chunks.extend_from_slice(&(4 + 24 * cue_points.len() as u32).to_le_bytes());
+    chunks.extend_from_slice(&(cue_points.len() as u32).to_le_bytes());
+    for (id, position) in cue_points {
+        chunks.extend_from_slice(&id.to_le_bytes());
+        chunks.extend_from_slice(&position.to_le_bytes());
+        chunks.extend_from_slice(b"data");
+        chunks.extend_from_slice(&0u32.to_le_bytes());
+        chunks.extend_from_slice(&0u32.to_le_bytes());
+        chunks.extend_from_slice(&position.to_le_bytes());
+    }
+
+    // Sampler chunk with a single forward loop that repeats forever
+    let sample_period = 1_000_000_000u32.checked_div(sample_rate).unwrap_or(0);
+    let smpl_words = [
+        0,                      // manufacturer
+        0,                      // product
+        sample_period,          // sample period in nanoseconds
+        MIDI_UNITY_NOTE as u32, // unity note
+        0,                      // pitch fraction
+        0,                      // SMPTE format
+        0,                      // SMPTE offset
+        1,                      // number of loops
+        0,                      // size of sampler specific data
+        // Loop
+        LOOP_START_CUE_ID, // cue point id
+        0,                 // type, 0 is forward
+        start,             // first sample of the loop
+        end,               // last sample of the loop
+        0,                 // fraction
+        0,                 // play count, 0 is infinite
+    ];
+    chunks.extend_from_slice(b"smpl");
+    chunks.extend_from_slice(&(smpl_words.len() as u32 * 4).to_le_bytes());
+    for word in smpl_words {
+        chunks.extend_from_slice(&word.to_le_bytes());
+    }
+
+    Ok(chunks)
+}
+
+/// Read a WAV file with integer samples of 8, 16, 24 or 32 bits, or float samples of 32 or
+/// 64 bits, with any number of channels. Samples are converted to 16 bits.
+///
+/// The first loop of a `smpl` chunk, if there is one, becomes the loop region. A `data` chunk
+/// shorter than its size, as left by some streaming tools, is read up to the end of the file.
+pub fn read_wav(bytes: &[u8]) -> Result<DecodedAudio, ParseError> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(ParseError::NotWAV);
+    }
+
+    let mut format = None;
+    let mut data = None;
+    let mut loop_region = None;
+    for (id, chunk) in riff_chunks(&bytes[12..]) {
+        match id {
+            b"fmt " => format = Some(WavFormat::parse(chunk)?),
+            b"data" => data = Some(chunk),
+            b"smpl" => loop_region = smpl_loop(chunk),
+            _ => {}
+        }
+    }
+    let (Some(format), Some(data)) = (format, data) else {
+        return Err(ParseError::NotWAV);
+    };
+
+    let bytes_per_sample = (format.bits_per_sample / 8) as usize;
+    let frame_size = bytes_per_sample * format.channels as usize;
+    let frame_count = data.len() / frame_size;
+    let samples = data[..frame_count * frame_size]
+        .chunks_exact(bytes_per_sample)
+        .map(|sample| format.sample_to_i16(sample))
+        .collect();
+
+    Ok(DecodedAudio {
+        samples,
+        sample_rate: format.sample_rate,
+        channels: format.channels,
+        loop_region: loop_region.and_then(|loop_region: LoopRegion| {
+            let end = loop_region.end.min(frame_count);
+            (loop_region.start < end).then_some(LoopRegion {
+                start: loop_region.start,
+                end,
+            })
+        }),
+    })
+}
+
+/// Same as [`read_wav`] but for a file in disk
+pub fn read_wav_file(filepath: &Path) -> Result<DecodedAudio, ParseError> {
+    read_wav(&fs::read(filepath)?)
+}
+
+/// Sample format of a WAV file, from its `fmt ` chunk
+struct WavFormat {
+    is_float: bool,
+    channels: u16,
+    sample_rate: u32,
+    bits_per_sample: u16,
+}
+
+impl WavFormat {
+    fn parse(chunk: &[u8]) -> Result<Self, ParseError> {
+        if chunk.len() < 16 {
+            return Err(ParseError::NotWAV);
+        }
+        let read_u16 = |offset: usize| u16::from_le_bytes([chunk[offset], chunk[offset + 1]]);
+
+        let mut format_tag = read_u16(0);
+        let channels = read_u16(2);
+        let sample_rate = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+        let bits_per_sample = read_u16(14);
+        // The sub format GUID is at 0x18, after the extension size and two more fields
+        if format_tag == WAVE_FORMAT_EXTENSIBLE && chunk.len() >= 0x1A {
+            format_tag = read_u16(0x18);
+        }
+
+        let is_float = match (format_tag, bits_per_sample) {
+            (WAVE_FORMAT_PCM, 8 | 16 | 24 | 32) => false,
+            (WAVE_FORMAT_IEEE_FLOAT, 32 | 64) => true,
+            _ => {
+                return Err(ParseError::UnsupportedWavFormat {
+                    format_tag,
+                    bits_per_sample,
+                })
+            }
+        };
+        if channels == 0 {
+            return Err(ParseError::NotWAV);
+        }
+
+        Ok(WavFormat {
+            is_float,
+            channels,
+            sample_rate,
+            bits_per_sample,
+        })
+    }
+
+    /// Convert a sample to 16 bits, rounding to the closest value
+    fn sample_to_i16(&self, sample: &[u8]) -> i16 {
+        let value = match (self.is_float, sample) {
+            (true, [a, b, c, d]) => f32::from_le_bytes([*a, *b, *c, *d]) as f64 * 32768.0,
+            (true, sample) => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(sample);
+                f64::from_le_bytes(bytes) * 32768.0
+            }
+            // 8 bits samples are the only unsigned ones
+            (false, [a]) => ((*a as i32 - 128) << 8) as f64,
+            (false, sample) => {
+                // Sign extend the sample to 32 bits, then scale it down to 16
+                let mut bytes = [0u8; 4];
+                bytes[4 - sample.len()..].copy_from_slice(sample);
+                i32::from_le_bytes(bytes) as f64 / 65536.0
+            }
+        };
+        value.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+    }
+}
+
+/// Iterate over the chunks of a RIFF file, as (id, data) pairs. Chunks are padded to an even
+/// size, and a chunk that goes past the end of the file is cut.
+fn riff_chunks(mut bytes: &[u8]) -> impl Iterator<Item = (&[u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let (header, rest) = bytes.split_first_chunk::<8>()?;
+        let (id, size) = header.split_first_chunk::<4>()?;
+        let size = u32::from_le_bytes(*<&[u8; 4]>::try_from(size).ok()?) as usize;
+
+        let data = &rest[..size.min(rest.len())];
+        bytes = &rest[(size + size % 2).min(rest.len())..];
+        Some((id, data))
+    })
+}
+
+/// First loop of a `smpl` chunk, see [`write_wav`] for its layout
+fn smpl_loop(chunk: &[u8]) -> Option<LoopRegion> {
+    let read_u32 = |offset: usize| {
+        let bytes = chunk.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    if read_u32(28)? == 0 {
+        return None;
+    }
+
+    // The loop starts at 36, its start and end (inclusive) are the 3rd and 4th words
+    let start = read_u32(36 + 8)? as usize;
+    let end = read_u32(36 + 12)? as usize;
+    (start <= end).then_some(LoopRegion {
+        start,
+        end: end + 1,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_wav_rejects_sample_rates_too_high_for_the_header() {
+        let audio = DecodedAudio {
+            samples: vec![0; 28],
+            sample_rate: u32::MAX,
+            channels: 1,
+            loop_region: None,
+        };
+
+        let error = write_wav(&mut vec![], &audio).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn write_wav_rejects_too_many_channels_for_the_header() {
+        let audio = |channels: u16| DecodedAudio {
+            samples: vec![0; channels as usize],
+            sample_rate: 22050,
+            channels,
+            loop_region: None,
+        };
+
+        assert!(write_wav(&mut vec![], &audio(4096)).is_ok());
+        let error = write_wav(&mut vec![], &audio(u16::MAX)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    /// A WAV file with a `fmt ` chunk for these fields, an odd sized chunk before the data
+    /// to check the padding, and `data`
+    fn wav(
+        format_tag: u16,
+        channels: u16,
+        bits_per_sample: u16,
+        extension: &[u8],
+        data: &[u8],
+    ) -> Vec<u8> {
+        let block_align = channels * bits_per_sample / 8;
+        let mut fmt = vec![];
+        fmt.extend(format_tag.to_le_bytes());
+        fmt.extend(channels.to_le_bytes());
+        fmt.extend(22050u32.to_le_bytes());
+        fmt.extend((22050 * block_align as u32).to_le_bytes());
+        fmt.extend(block_align.to_le_bytes());
+        fmt.extend(bits_per_sample.to_le_bytes());
+        fmt.extend(extension);
+
+        let mut chunks = b"WAVE".to_vec();
+        for (id, chunk) in [(b"fmt ", &fmt[..]), (b"LIST", b"odd"), (b"data", data)] {
+            chunks.extend(id);
+            chunks.extend((chunk.len() as u32).to_le_bytes());
+            chunks.extend(chunk);
+            if chunk.len() % 2 == 1 {
+                chunks.push(0);
+            }
+        }
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend((chunks.len() as u32).to_le_bytes());
+        bytes.extend(chunks);
+        bytes
+    }
+
+    fn read_samples(bytes: &[u8]) -> Vec<i16> {
+        let audio = read_wav(bytes).unwrap();
+        assert_eq!(audio.sample_rate, 22050);
+        audio.samples
+    }
+
+    #[test]
+    fn read_wav_converts_integer_samples_to_16_bits() {
+        // 8 bits samples are unsigned, centered at 128
+        let bytes = wav(WAVE_FORMAT_PCM, 1, 8, &[], &[0x80, 0xFF, 0x00, 0x81]);
+        assert_eq!(read_samples(&bytes), [0, 32512, -32768, 256]);
+
+        let bytes = wav(
+            WAVE_FORMAT_PCM,
+            1,
+            16,
+            &[],
+            &[0x00, 0x80, 0xFF, 0x7F, 0x34, 0x12],
+        );
+        assert_eq!(read_samples(&bytes), [-32768, 32767, 0x1234]);
+
+        // Lower bits are rounded to the closest value
+        let data = [0x56, 0x34, 0x12, 0x80, 0x00, 0x80, 0xFF, 0xFF, 0xFF];
+        let bytes = wav(WAVE_FORMAT_PCM, 1, 24, &[], &data);
+        assert_eq!(read_samples(&bytes), [0x1234, -32768, 0]);
+
+        let data = [
+            0x00, 0x80, 0x34, 0x12, 0xFF, 0xFF, 0xFF, 0x7F, 0x00, 0x00, 0x00, 0x80,
+        ];
+        let bytes = wav(WAVE_FORMAT_PCM, 1, 32, &[], &data);
+        assert_eq!(read_samples(&bytes), [0x1235, 32767, -32768]);
+    }
+
+    #[test]
+    fn read_wav_converts_float_samples_to_16_bits() {
+        let data: Vec<u8> = [0.5f32, -1.0, 1.0, 0.25]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let bytes = wav(WAVE_FORMAT_IEEE_FLOAT, 1, 32, &[], &data);
+        assert_eq!(read_samples(&bytes), [16384, -32768, 32767, 8192]);
+
+        let data: Vec<u8> = [-0.5f64, 2.0]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let bytes = wav(WAVE_FORMAT_IEEE_FLOAT, 1, 64, &[], &data);
+        assert_eq!(read_samples(&bytes), [-16384, 32767]);
+    }
+
+    #[test]
+    fn read_wav_uses_the_sub_format_of_extensible_files() {
+        // Extension size, valid bits, channel mask and the GUID, starting with the format tag
+        let extension = |format_tag: u16, bits_per_sample: u16| {
+            let mut extension = vec![];
+            extension.extend(22u16.to_le_bytes());
+            extension.extend(bits_per_sample.to_le_bytes());
+            extension.extend(3u32.to_le_bytes());
+            extension.extend(format_tag.to_le_bytes());
+            extension.extend([
+                0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+            ]);
+            extension
+        };
+
+        let data = [0x01, 0x00, 0xFF, 0xFF, 0x00, 0x40, 0x00, 0xC0];
+        let bytes = wav(
+            WAVE_FORMAT_EXTENSIBLE,
+            2,
+            16,
+            &extension(WAVE_FORMAT_PCM, 16),
+            &data,
+        );
+        let audio = read_wav(&bytes).unwrap();
+        assert_eq!(audio.channels, 2);
+        assert_eq!(audio.frame_count(), 2);
+        assert_eq!(audio.samples, [1, -1, 16384, -16384]);
+
+        let data: Vec<u8> = [0.5f32, -0.5]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let bytes = wav(
+            WAVE_FORMAT_EXTENSIBLE,
+            2,
+            32,
+            &extension(WAVE_FORMAT_IEEE_FLOAT, 32),
+            &data,
+        );
+        assert_eq!(read_samples(&bytes), [16384, -16384]);
+
+        // ADPCM, inside an extensible file or not
+        for bytes in [
+            wav(2, 1, 4, &[], &[0; 4]),
+            wav(WAVE_FORMAT_EXTENSIBLE, 1, 4, &extension(2, 4), &[0; 4]),
+        ] {
+            assert!(matches!(
+                read_wav(&bytes),
+                Err(ParseError::UnsupportedWavFormat {
+                    format_tag: 2,
+                    bits_per_sample: 4
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn written_loops_are_read_back() {
+        let audio = DecodedAudio {
+            samples: (0..200).map(|i| i * 10 - 1000).collect(),
+            sample_rate: 32000,
+            channels: 1,
+            loop_region: Some(LoopRegion {
+                start: 28,
+                end: 168,
+            }),
+        };
+        let mut bytes = vec![];
+        write_wav(&mut bytes, &audio).unwrap();
+
+        assert_eq!(read_wav(&bytes).unwrap(), audio);
+
+        // Loops past the end of the data are cut to it
+        let mut looped_past_the_end = audio.clone();
+        looped_past_the_end.loop_region = Some(LoopRegion {
+            start: 28,
+            end: 300,
+        });
+        let mut bytes = vec![];
+        write_wav(&mut bytes, &looped_past_the_end).unwrap();
+        let read = read_wav(&bytes).unwrap();
+        assert_eq!(
+            read.loop_region,
+            Some(LoopRegion {
+                start: 28,
+                end: 200
+            })
+        );
+    }
+}